@@ -1,7 +1,6 @@
 use std::sync::Arc;
 
-mod vk;
-use vk::VkApp;
+use rengine::vk::VkApp;
 
 fn main() {
     let mut app = VkApp::new();
@@ -5,10 +5,11 @@ use vulkano::command_buffer::allocator::{StandardCommandBufferAllocator, Standar
 use vulkano::command_buffer::{AutoCommandBufferBuilder, CommandBufferUsage, CopyBufferInfo, PrimaryAutoCommandBuffer, CommandBufferExecFuture, PrimaryCommandBufferAbstract};
 use vulkano::device::Queue;
 use vulkano::memory::MemoryPropertyFlags;
-use vulkano::sync::{self, GpuFuture};
+use vulkano::sync::{self, GpuFuture, Sharing};
 use std::sync::Arc;
 
 use crate::vk::Vert;
+use crate::vk::debug;
 
 pub type PrimaryCommandBufferBuilder = AutoCommandBufferBuilder<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>, Arc<StandardCommandBufferAllocator>>;
 
@@ -31,7 +32,7 @@ pub const UNIFORM_BUFFER_MEMORY_TYPE_FILTER: MemoryTypeFilter = MemoryTypeFilter
 };
 
 pub fn create_buffer(memory_allocator: Arc<StandardMemoryAllocator>, memory_type_filter: MemoryTypeFilter, buffer_usage: BufferUsage) -> Subbuffer<f32> {
-    Buffer::new_sized::<f32>(
+    let buffer = Buffer::new_sized::<f32>(
         memory_allocator.clone(),
         BufferCreateInfo{
             usage: buffer_usage,
@@ -41,7 +42,15 @@ pub fn create_buffer(memory_allocator: Arc<StandardMemoryAllocator>, memory_type
             memory_type_filter: memory_type_filter,
             ..Default::default()
         },
-    ).expect("Failed to create buffer")
+    ).expect("Failed to create buffer");
+    name_buffer(&buffer, buffer_usage);
+    buffer
+}
+
+// Default debug name, i.e. `VERTEX_BUFFER [rengine::vk::Vert; 4]`
+fn name_buffer<T: BufferContents + ?Sized>(buffer: &Subbuffer<T>, buffer_usage: BufferUsage) {
+    let name = format!("{:?} {}", buffer_usage, std::any::type_name::<T>());
+    debug::set_object_name(&**buffer.buffer(), &name);
 }
 
 pub fn create_buffer_from_iter<T>(
@@ -53,7 +62,7 @@ pub fn create_buffer_from_iter<T>(
 where 
     T: BufferContents + Copy,
 {
-    Buffer::from_iter(
+    let buffer = Buffer::from_iter(
         memory_allocator.clone(),
         BufferCreateInfo{
             usage: buffer_usage,
@@ -64,19 +73,80 @@ where
             ..Default::default()
         },
         iter,
-    ).expect("Failed to create buffer")
+    ).expect("Failed to create buffer");
+    name_buffer(&buffer, buffer_usage);
+    buffer
 }
 
-pub fn create_vertex_buffer(memory_allocator: Arc<StandardMemoryAllocator>, verts_iter: impl ExactSizeIterator<Item = Vert>) -> Arc<Subbuffer<[Vert]>> {
+// Buffer usable from several queue families without ownership transfers, i.e. filled on the
+// transfer queue and read on the graphics queue
+// Concurrent sharing is the only supported mode for buffers used across queue families, there is no
+// release/acquire barrier helper, so an exclusive buffer must stay on the queue family that created it
+pub fn create_shared_buffer_from_iter<T>(
+    memory_allocator: Arc<StandardMemoryAllocator>, 
+    memory_type_filter: MemoryTypeFilter, 
+    buffer_usage: BufferUsage, 
+    queue_family_indices: &[u32],
+    iter: impl ExactSizeIterator<Item = T>
+) -> Subbuffer<[T]> 
+where 
+    T: BufferContents + Copy,
+{
+    let sharing = if queue_family_indices.len() > 1 {
+        Sharing::Concurrent(queue_family_indices.iter().copied().collect())
+    } else {
+        Sharing::Exclusive
+    };
+    let buffer = Buffer::from_iter(
+        memory_allocator.clone(),
+        BufferCreateInfo{
+            usage: buffer_usage,
+            sharing,
+            ..Default::default()
+        },
+        AllocationCreateInfo{
+            memory_type_filter: memory_type_filter,
+            ..Default::default()
+        },
+        iter,
+    ).expect("Failed to create buffer");
+    name_buffer(&buffer, buffer_usage);
+    buffer
+}
+
+// Host visible so it can be rewritten every frame
+pub fn create_uniform_buffer<T: BufferContents>(memory_allocator: Arc<StandardMemoryAllocator>, data: T) -> Subbuffer<T> {
+    let buffer = Buffer::from_data(
+        memory_allocator.clone(),
+        BufferCreateInfo{
+            usage: BufferUsage::UNIFORM_BUFFER,
+            ..Default::default()
+        },
+        AllocationCreateInfo{
+            memory_type_filter: UNIFORM_BUFFER_MEMORY_TYPE_FILTER,
+            ..Default::default()
+        },
+        data,
+    ).expect("Failed to create uniform buffer");
+    name_buffer(&buffer, BufferUsage::UNIFORM_BUFFER);
+    buffer
+}
+
+pub fn create_vertex_buffer(memory_allocator: Arc<StandardMemoryAllocator>, verts_iter: impl ExactSizeIterator<Item = Vert>) -> Subbuffer<[Vert]> {
     let memory_type_filter = UNIFORM_BUFFER_MEMORY_TYPE_FILTER;//MemoryTypeFilter::PREFER_DEVICE;
-    Arc::new(create_buffer_from_iter(memory_allocator, memory_type_filter, BufferUsage::VERTEX_BUFFER, verts_iter))
+    create_buffer_from_iter(memory_allocator, memory_type_filter, BufferUsage::VERTEX_BUFFER, verts_iter)
 }
 
-pub fn create_index_buffer(memory_allocator: Arc<StandardMemoryAllocator>, indices_iter: impl ExactSizeIterator<Item = u32>) -> Arc<IndexBuffer> {
+// u16 or u32 indices
+pub fn create_index_buffer<T>(memory_allocator: Arc<StandardMemoryAllocator>, indices_iter: impl ExactSizeIterator<Item = T>) -> IndexBuffer
+where
+    T: BufferContents + Copy,
+    IndexBuffer: From<Subbuffer<[T]>>,
+{
     let memory_type_filter = UNIFORM_BUFFER_MEMORY_TYPE_FILTER; //MemoryTypeFilter::PREFER_DEVICE;
-    Arc::new(IndexBuffer::from(
+    IndexBuffer::from(
         create_buffer_from_iter(memory_allocator, memory_type_filter, BufferUsage::INDEX_BUFFER, indices_iter)
-    ))
+    )
 }
 
 //https://docs.rs/vulkano/0.34.0/vulkano/command_buffer/index.html
@@ -121,3 +191,17 @@ pub fn submit_execute_wait_fenced(device: Arc<Device>, queue: Arc<Queue>, comman
 
     future.wait(None).unwrap();
 }
+
+// Executes the command buffer on the queue once previous has finished, previous can be work on another queue
+// A semaphore is signalled between the two when the queues differ, so the returned future can keep being
+// chained across queues (i.e. transfer upload -> compute -> graphics)
+// Only synchronises execution, resources touched on both queues must be created with
+// create_shared_buffer_from_iter since no queue family ownership transfer is recorded
+pub fn submit_after(previous: Box<dyn GpuFuture>, queue: Arc<Queue>, command_buffer: Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>>) -> Box<dyn GpuFuture> {
+    let previous = if previous.queue().is_some_and(|previous_queue| previous_queue != queue) {
+        Box::new(previous.then_signal_semaphore_and_flush().unwrap()) as Box<dyn GpuFuture>
+    } else {
+        previous
+    };
+    Box::new(previous.then_execute(queue, command_buffer).unwrap())
+}
@@ -0,0 +1,199 @@
+use glam::{Mat4, Quat, Vec3, EulerRot};
+use vulkano::buffer::BufferContents;
+use vulkano::pipeline::graphics::viewport::Viewport;
+use winit::event::{MouseButton, VirtualKeyCode};
+
+use crate::vk::input::Input;
+
+// Where shaders find the camera uniform, i.e. `layout(set = 0, binding = 0) uniform Camera { ... }`
+pub const CAMERA_SET: u32 = 0;
+pub const CAMERA_BINDING: u32 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    // Vertical field of view in radians
+    Perspective { fov_y: f32, near: f32, far: f32 },
+    // Height of the view volume in world units, the width follows the aspect ratio
+    Orthographic { height: f32, near: f32, far: f32 },
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::Perspective { fov_y: 60f32.to_radians(), near: 0.1, far: 1000.0 }
+    }
+}
+
+// Right handed, looks down -Z when the rotation is the identity
+#[derive(Clone, Debug)]
+pub struct Camera {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub projection: Projection,
+    // Width over height, kept up to date from the window's viewport
+    pub aspect: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            position: Vec3::new(0.0, 0.0, 2.0),
+            rotation: Quat::IDENTITY,
+            projection: Projection::default(),
+            aspect: 1.0,
+        }
+    }
+}
+
+impl Camera {
+    pub fn new(projection: Projection) -> Self {
+        Camera { projection, ..Default::default() }
+    }
+
+    pub fn forward(&self) -> Vec3 {
+        self.rotation * Vec3::NEG_Z
+    }
+
+    pub fn right(&self) -> Vec3 {
+        self.rotation * Vec3::X
+    }
+
+    pub fn up(&self) -> Vec3 {
+        self.rotation * Vec3::Y
+    }
+
+    pub fn look_at(&mut self, target: Vec3) {
+        let view = Mat4::look_at_rh(self.position, target, Vec3::Y);
+        self.rotation = Quat::from_mat4(&view.inverse());
+    }
+
+    pub fn set_viewport(&mut self, viewport: &Viewport) {
+        if viewport.extent[1] > 0.0 {
+            self.aspect = viewport.extent[0] / viewport.extent[1];
+        }
+    }
+
+    pub fn view_matrix(&self) -> Mat4 {
+        Mat4::look_to_rh(self.position, self.forward(), self.up())
+    }
+
+    // Depth goes from 0 at the near plane to 1 at the far plane and Y is flipped, both as Vulkan expects
+    pub fn projection_matrix(&self) -> Mat4 {
+        let mut projection = match self.projection {
+            Projection::Perspective { fov_y, near, far } => Mat4::perspective_rh(fov_y, self.aspect, near, far),
+            Projection::Orthographic { height, near, far } => {
+                let half_height = height / 2.0;
+                let half_width = half_height * self.aspect;
+                Mat4::orthographic_rh(-half_width, half_width, -half_height, half_height, near, far)
+            }
+        };
+        projection.y_axis.y = -projection.y_axis.y;
+        projection
+    }
+
+    pub fn view_projection_matrix(&self) -> Mat4 {
+        self.projection_matrix() * self.view_matrix()
+    }
+
+    pub fn uniform(&self) -> CameraUniform {
+        CameraUniform {
+            view: self.view_matrix().to_cols_array_2d(),
+            projection: self.projection_matrix().to_cols_array_2d(),
+            view_projection: self.view_projection_matrix().to_cols_array_2d(),
+            position: self.position.extend(1.0).to_array(),
+        }
+    }
+}
+
+// Column major, matches a std140 block with three mat4 and a vec4
+#[derive(BufferContents, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct CameraUniform {
+    pub view: [[f32; 4]; 4],
+    pub projection: [[f32; 4]; 4],
+    pub view_projection: [[f32; 4]; 4],
+    pub position: [f32; 4],
+}
+
+// WASD to move, Space and Left Shift to go up and down, looks around with the mouse while the right button
+// is held or the cursor is grabbed
+#[derive(Clone, Debug)]
+pub struct FlyCamera {
+    pub yaw: f32,
+    pub pitch: f32,
+    // World units per second
+    pub speed: f32,
+    // Radians per pixel of mouse movement
+    pub sensitivity: f32,
+}
+
+impl Default for FlyCamera {
+    fn default() -> Self {
+        FlyCamera { yaw: 0.0, pitch: 0.0, speed: 3.0, sensitivity: 0.003 }
+    }
+}
+
+impl FlyCamera {
+    pub fn update(&mut self, camera: &mut Camera, input: &Input, dt: f32) {
+        if input.is_mouse_held(MouseButton::Right) || input.is_cursor_grabbed() {
+            let [dx, dy] = input.cursor_delta();
+            self.yaw -= dx * self.sensitivity;
+            self.pitch = (self.pitch - dy * self.sensitivity).clamp(-1.55, 1.55);
+        }
+        camera.rotation = Quat::from_euler(EulerRot::YXZ, self.yaw, self.pitch, 0.0);
+
+        let mut direction = Vec3::ZERO;
+        if input.is_key_held(VirtualKeyCode::W) { direction += camera.forward(); }
+        if input.is_key_held(VirtualKeyCode::S) { direction -= camera.forward(); }
+        if input.is_key_held(VirtualKeyCode::D) { direction += camera.right(); }
+        if input.is_key_held(VirtualKeyCode::A) { direction -= camera.right(); }
+        if input.is_key_held(VirtualKeyCode::Space) { direction += Vec3::Y; }
+        if input.is_key_held(VirtualKeyCode::LShift) { direction -= Vec3::Y; }
+        camera.position += direction.normalize_or_zero() * self.speed * dt;
+    }
+}
+
+// Rotates around target while the left button is held, pans with the middle button and zooms with the wheel
+#[derive(Clone, Debug)]
+pub struct OrbitCamera {
+    pub target: Vec3,
+    pub distance: f32,
+    pub yaw: f32,
+    pub pitch: f32,
+    // Radians per pixel of mouse movement
+    pub sensitivity: f32,
+    // Fraction of the distance per scroll line
+    pub zoom_speed: f32,
+    pub min_distance: f32,
+}
+
+impl Default for OrbitCamera {
+    fn default() -> Self {
+        OrbitCamera {
+            target: Vec3::ZERO,
+            distance: 3.0,
+            yaw: 0.0,
+            pitch: -0.3,
+            sensitivity: 0.005,
+            zoom_speed: 0.1,
+            min_distance: 0.05,
+        }
+    }
+}
+
+impl OrbitCamera {
+    pub fn update(&mut self, camera: &mut Camera, input: &Input) {
+        let [dx, dy] = input.cursor_delta();
+        if input.is_mouse_held(MouseButton::Left) {
+            self.yaw -= dx * self.sensitivity;
+            self.pitch = (self.pitch - dy * self.sensitivity).clamp(-1.55, 1.55);
+        }
+        camera.rotation = Quat::from_euler(EulerRot::YXZ, self.yaw, self.pitch, 0.0);
+        if input.is_mouse_held(MouseButton::Middle) {
+            let pan_speed = self.distance * self.sensitivity * 0.2;
+            self.target += (camera.up() * dy - camera.right() * dx) * pan_speed;
+        }
+        let [_, scroll] = input.scroll();
+        self.distance = (self.distance * (1.0 - scroll * self.zoom_speed)).max(self.min_distance);
+        camera.position = self.target - camera.forward() * self.distance;
+    }
+}
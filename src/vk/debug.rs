@@ -0,0 +1,173 @@
+use vulkano::VulkanLibrary;
+use vulkano::instance::Instance;
+use vulkano::instance::debug::{
+    DebugUtilsMessenger, DebugUtilsMessengerCreateInfo, DebugUtilsMessengerCallback,
+    DebugUtilsMessageSeverity, DebugUtilsMessageType, DebugUtilsLabel,
+};
+use vulkano::device::DeviceOwned;
+use vulkano::VulkanObject;
+use std::sync::{Arc, Mutex};
+use crate::vk::buffer::PrimaryCommandBufferBuilder;
+
+pub const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+
+// Opt-in debug mode, off by default
+#[derive(Clone)]
+pub struct DebugConfig {
+    pub enabled: bool,
+    // Enables VK_LAYER_KHRONOS_validation if it is installed
+    pub validation: bool,
+    // Messages below this level are not logged
+    pub min_level: log::Level,
+    // Keep validation errors around so tests can assert on them with ValidationErrors::assert_none
+    pub record_validation_errors: bool,
+}
+
+impl Default for DebugConfig {
+    fn default() -> Self {
+        DebugConfig {
+            enabled: false,
+            validation: true,
+            min_level: log::Level::Warn,
+            record_validation_errors: false,
+        }
+    }
+}
+
+impl DebugConfig {
+    pub fn enabled() -> Self {
+        DebugConfig { enabled: true, ..Default::default() }
+    }
+
+    fn message_severity(&self) -> DebugUtilsMessageSeverity {
+        let mut severity = DebugUtilsMessageSeverity::ERROR;
+        if self.min_level >= log::Level::Warn {
+            severity |= DebugUtilsMessageSeverity::WARNING;
+        }
+        if self.min_level >= log::Level::Info {
+            severity |= DebugUtilsMessageSeverity::INFO;
+        }
+        if self.min_level >= log::Level::Debug {
+            severity |= DebugUtilsMessageSeverity::VERBOSE;
+        }
+        severity
+    }
+}
+
+// Validation errors reported by the messenger, shared with the callback
+#[derive(Clone, Default)]
+pub struct ValidationErrors(Arc<Mutex<Vec<String>>>);
+
+impl ValidationErrors {
+    pub fn count(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    // Panics with every recorded message, meant to be called at the end of a test
+    pub fn assert_none(&self) {
+        let errors = self.take();
+        if !errors.is_empty() {
+            panic!("{} validation error(s):\n{}", errors.len(), errors.join("\n"));
+        }
+    }
+
+    fn push(&self, message: String) {
+        self.0.lock().unwrap().push(message);
+    }
+}
+
+// Returns the layers to enable, the validation layer is skipped with a warning if it isn't installed
+pub fn instance_layers(library: &VulkanLibrary, config: &DebugConfig) -> Vec<String> {
+    if !config.enabled || !config.validation {
+        return Vec::new();
+    }
+    let available = library.layer_properties()
+        .map(|layers| layers.into_iter().any(|layer| layer.name() == VALIDATION_LAYER))
+        .unwrap_or(false);
+    if !available {
+        log::warn!("{} requested but not available", VALIDATION_LAYER);
+        return Vec::new();
+    }
+    vec![VALIDATION_LAYER.to_string()]
+}
+
+// Routes debug utils messages to the log crate
+// The instance needs ext_debug_utils enabled
+pub fn create_debug_messenger(instance: Arc<Instance>, config: &DebugConfig, validation_errors: ValidationErrors) -> DebugUtilsMessenger {
+    let record_validation_errors = config.record_validation_errors;
+    // Safety: the callback only logs and records, it never calls into Vulkan
+    let callback = unsafe {
+        DebugUtilsMessengerCallback::new(move |severity, message_type, data| {
+            let level = if severity.intersects(DebugUtilsMessageSeverity::ERROR) {
+                log::Level::Error
+            } else if severity.intersects(DebugUtilsMessageSeverity::WARNING) {
+                log::Level::Warn
+            } else if severity.intersects(DebugUtilsMessageSeverity::INFO) {
+                log::Level::Info
+            } else {
+                log::Level::Debug
+            };
+            let id = data.message_id_name.unwrap_or("");
+            log::log!(target: "vulkan", level, "[{:?}] {} {}", message_type, id, data.message);
+
+            if record_validation_errors
+                && level == log::Level::Error
+                && message_type.intersects(DebugUtilsMessageType::VALIDATION)
+            {
+                validation_errors.push(format!("{} {}", id, data.message));
+            }
+        })
+    };
+
+    DebugUtilsMessenger::new(
+        instance,
+        DebugUtilsMessengerCreateInfo {
+            message_severity: config.message_severity(),
+            message_type: DebugUtilsMessageType::GENERAL
+                | DebugUtilsMessageType::VALIDATION
+                | DebugUtilsMessageType::PERFORMANCE,
+            ..DebugUtilsMessengerCreateInfo::user_callback(callback)
+        },
+    ).expect("Failed to create debug messenger")
+}
+
+// Names and labels need ext_debug_utils on the instance, without it they are skipped
+fn debug_utils_enabled<T: DeviceOwned>(object: &T) -> bool {
+    object.device().instance().enabled_extensions().ext_debug_utils
+}
+
+// Gives the object a name that shows up in validation messages and capture tools
+pub fn set_object_name<T: VulkanObject + DeviceOwned>(object: &T, name: &str) {
+    if !debug_utils_enabled(object) {
+        return;
+    }
+    if let Err(e) = object.device().set_debug_utils_object_name(object, Some(name)) {
+        log::warn!("Failed to name object `{}`: {}", name, e);
+    }
+}
+
+// Opens a label region in the command buffer, close it with end_label
+pub fn begin_label(mut builder: PrimaryCommandBufferBuilder, name: &str, color: [f32; 4]) -> PrimaryCommandBufferBuilder {
+    if debug_utils_enabled(&builder) {
+        builder.begin_debug_utils_label(DebugUtilsLabel {
+            label_name: name.to_string(),
+            color,
+            ..Default::default()
+        }).unwrap();
+    }
+    builder
+}
+
+pub fn end_label(mut builder: PrimaryCommandBufferBuilder) -> PrimaryCommandBufferBuilder {
+    if debug_utils_enabled(&builder) {
+        // Safety: only called to close a region opened by begin_label in the same builder
+        unsafe {
+            builder.end_debug_utils_label().unwrap();
+        }
+    }
+    builder
+}
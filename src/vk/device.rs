@@ -1,20 +1,321 @@
 use vulkano::instance::Instance;
-use vulkano::device::{Device, DeviceCreateInfo, DeviceExtensions, QueueCreateInfo, Queue};
+use vulkano::device::{Device, DeviceCreateInfo, DeviceExtensions, Features, QueueCreateInfo, Queue};
 use vulkano::device::QueueFlags;
 use vulkano::device::physical::{PhysicalDevice, PhysicalDeviceType};
 use vulkano::swapchain::Surface;
-use vulkano::swapchain::SurfaceCapabilities;
+use vulkano::Version;
+use std::fmt;
 use std::sync::Arc;
 
-// Creates a device and a queue
-// Note, takes the first physical device it finds and uses the first queue family that supports graphics
-// Also, takes the first queue in the first queue family that supports graphics
-pub fn create_device(instance: Arc<Instance>, device_extensions: DeviceExtensions, surface: Arc<Surface>) -> (Arc<Device>, Arc<Queue>, u32, Arc<PhysicalDevice>) {
+// Environment variable that overrides the configured device selection
+// Accepts "cpu", "index:N", "VENDOR:DEVICE" (hex ids, i.e. "0x10de:0x2684") or a name substring
+pub const DEVICE_SELECTION_ENV_VAR: &str = "RENGINE_DEVICE";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeviceSelection {
+    // Prefer discrete, then integrated, virtual and CPU devices
+    #[default]
+    Auto,
+    // Case insensitive substring of the device name
+    Name(String),
+    // Index into the instance's physical device list
+    Index(usize),
+    Id { vendor_id: u32, device_id: u32 },
+    // Software rasterizer, i.e. lavapipe
+    Cpu,
+}
+
+impl DeviceSelection {
+    pub fn from_env() -> Option<DeviceSelection> {
+        std::env::var(DEVICE_SELECTION_ENV_VAR).ok().map(|value| DeviceSelection::parse(&value))
+    }
+
+    pub fn parse(value: &str) -> DeviceSelection {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("cpu") {
+            return DeviceSelection::Cpu;
+        }
+        if value.eq_ignore_ascii_case("auto") || value.is_empty() {
+            return DeviceSelection::Auto;
+        }
+        if let Some(index) = value.strip_prefix("index:").and_then(|index| index.parse().ok()) {
+            return DeviceSelection::Index(index);
+        }
+        if let Some((vendor_id, device_id)) = value.split_once(':') {
+            if let (Some(vendor_id), Some(device_id)) = (parse_hex_id(vendor_id), parse_hex_id(device_id)) {
+                return DeviceSelection::Id { vendor_id, device_id };
+            }
+        }
+        DeviceSelection::Name(value.to_string())
+    }
+
+    fn matches(&self, index: usize, physical_device: &PhysicalDevice) -> bool {
+        let properties = physical_device.properties();
+        match self {
+            DeviceSelection::Auto => true,
+            DeviceSelection::Name(name) => properties.device_name.to_lowercase().contains(&name.to_lowercase()),
+            DeviceSelection::Index(selected) => index == *selected,
+            DeviceSelection::Id { vendor_id, device_id } => properties.vendor_id == *vendor_id && properties.device_id == *device_id,
+            DeviceSelection::Cpu => properties.device_type == PhysicalDeviceType::Cpu,
+        }
+    }
+}
+
+fn parse_hex_id(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let value = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+    u32::from_str_radix(value, 16).ok()
+}
+
+// Features and extensions the app asks the device for
+// Devices lacking a required one are never selected, optional ones are enabled when supported
+// Check Device::enabled_features/enabled_extensions for what was actually enabled
+#[derive(Clone)]
+pub struct DeviceRequirements {
+    pub required_extensions: DeviceExtensions,
+    pub optional_extensions: DeviceExtensions,
+    pub required_features: Features,
+    pub optional_features: Features,
+    // Extensions a feature can't be enabled without, enabled alongside the feature
+    // Optional features are dropped when the device doesn't support their extensions
+    pub feature_extensions: Vec<(Features, DeviceExtensions)>,
+}
+
+impl Default for DeviceRequirements {
+    fn default() -> Self {
+        DeviceRequirements {
+            required_extensions: DeviceExtensions::empty(),
+            optional_extensions: DeviceExtensions::empty(),
+            required_features: Features::empty(),
+            optional_features: Features::empty(),
+            feature_extensions: default_feature_extensions(),
+        }
+    }
+}
+
+// Features this app might ask for that live in an extension rather than core Vulkan
+pub fn default_feature_extensions() -> Vec<(Features, DeviceExtensions)> {
+    vec![
+        (
+            Features { acceleration_structure: true, ..Features::empty() },
+            DeviceExtensions { khr_acceleration_structure: true, ..DeviceExtensions::empty() },
+        ),
+        (
+            Features { ray_tracing_pipeline: true, ..Features::empty() },
+            DeviceExtensions { khr_ray_tracing_pipeline: true, ..DeviceExtensions::empty() },
+        ),
+        (
+            Features { ray_query: true, ..Features::empty() },
+            DeviceExtensions { khr_ray_query: true, ..DeviceExtensions::empty() },
+        ),
+        (
+            Features { task_shader: true, mesh_shader: true, ..Features::empty() },
+            DeviceExtensions { ext_mesh_shader: true, ..DeviceExtensions::empty() },
+        ),
+        (
+            Features { extended_dynamic_state: true, ..Features::empty() },
+            DeviceExtensions { ext_extended_dynamic_state: true, ..DeviceExtensions::empty() },
+        ),
+        (
+            Features { fragment_shading_rate_enums: true, ..Features::empty() },
+            DeviceExtensions { nv_fragment_shading_rate_enums: true, ..DeviceExtensions::empty() },
+        ),
+    ]
+}
+
+impl DeviceRequirements {
+    pub fn is_supported_by(&self, physical_device: &PhysicalDevice) -> bool {
+        let required_extensions = self.extensions_needed_by(&self.required_features).union(&self.required_extensions);
+        physical_device.supported_extensions().contains(&required_extensions)
+            && physical_device.supported_features().contains(&self.required_features)
+    }
+
+    // Required plus whichever optional extensions the device supports, plus the ones the enabled features need
+    pub fn extensions_for(&self, physical_device: &PhysicalDevice) -> DeviceExtensions {
+        self.enabled_extensions(physical_device.supported_extensions(), physical_device.supported_features())
+    }
+
+    // Required plus whichever optional features the device supports along with their extensions
+    pub fn features_for(&self, physical_device: &PhysicalDevice) -> Features {
+        self.enabled_features(physical_device.supported_extensions(), physical_device.supported_features())
+    }
+
+    fn enabled_extensions(&self, supported_extensions: &DeviceExtensions, supported_features: &Features) -> DeviceExtensions {
+        let features = self.enabled_features(supported_extensions, supported_features);
+        self.required_extensions
+            .union(&self.optional_extensions.intersection(supported_extensions))
+            .union(&self.extensions_needed_by(&features))
+    }
+
+    fn enabled_features(&self, supported_extensions: &DeviceExtensions, supported_features: &Features) -> Features {
+        let mut optional_features = self.optional_features.intersection(supported_features);
+        for (features, extensions) in &self.feature_extensions {
+            if !supported_extensions.contains(extensions) {
+                optional_features = optional_features.difference(features);
+            }
+        }
+        self.required_features.union(&optional_features)
+    }
+
+    fn extensions_needed_by(&self, enabled_features: &Features) -> DeviceExtensions {
+        self.feature_extensions.iter()
+            .filter(|(features, _)| enabled_features.intersects(features))
+            .fold(DeviceExtensions::empty(), |needed, (_, extensions)| needed.union(extensions))
+    }
+}
+
+pub struct QueueFamilyReport {
+    pub index: u32,
+    pub queue_flags: QueueFlags,
+    pub queue_count: u32,
+}
+
+// Everything worth knowing about a physical device when picking one
+pub struct PhysicalDeviceReport {
+    pub index: usize,
+    pub name: String,
+    pub device_type: PhysicalDeviceType,
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub api_version: Version,
+    pub driver_version: u32,
+    pub driver_info: Option<String>,
+    pub max_image_dimension2_d: u32,
+    pub max_push_constants_size: u32,
+    pub max_bound_descriptor_sets: u32,
+    pub max_uniform_buffer_range: u32,
+    pub max_storage_buffer_range: u32,
+    pub max_compute_work_group_count: [u32; 3],
+    pub max_compute_work_group_size: [u32; 3],
+    pub max_compute_work_group_invocations: u32,
+    pub max_sampler_anisotropy: f32,
+    pub device_local_memory: u64,
+    pub queue_families: Vec<QueueFamilyReport>,
+    pub extensions: Vec<&'static str>,
+    pub physical_device: Arc<PhysicalDevice>,
+}
+
+impl PhysicalDeviceReport {
+    pub fn new(index: usize, physical_device: Arc<PhysicalDevice>) -> Self {
+        let properties = physical_device.properties();
+        let device_local_memory = physical_device.memory_properties().memory_heaps.iter()
+            .filter(|heap| heap.flags.intersects(vulkano::memory::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|heap| heap.size)
+            .sum();
+        let queue_families = physical_device.queue_family_properties().iter()
+            .enumerate()
+            .map(|(i, q)| QueueFamilyReport { index: i as u32, queue_flags: q.queue_flags, queue_count: q.queue_count })
+            .collect();
+        let extensions = physical_device.supported_extensions().into_iter()
+            .filter(|(_, supported)| *supported)
+            .map(|(name, _)| name)
+            .collect();
+
+        PhysicalDeviceReport {
+            index,
+            name: properties.device_name.clone(),
+            device_type: properties.device_type,
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            api_version: properties.api_version,
+            driver_version: properties.driver_version,
+            driver_info: properties.driver_info.clone(),
+            max_image_dimension2_d: properties.max_image_dimension2_d,
+            max_push_constants_size: properties.max_push_constants_size,
+            max_bound_descriptor_sets: properties.max_bound_descriptor_sets,
+            max_uniform_buffer_range: properties.max_uniform_buffer_range,
+            max_storage_buffer_range: properties.max_storage_buffer_range,
+            max_compute_work_group_count: properties.max_compute_work_group_count,
+            max_compute_work_group_size: properties.max_compute_work_group_size,
+            max_compute_work_group_invocations: properties.max_compute_work_group_invocations,
+            max_sampler_anisotropy: properties.max_sampler_anisotropy,
+            device_local_memory,
+            queue_families,
+            extensions,
+            physical_device,
+        }
+    }
+}
+
+impl fmt::Display for PhysicalDeviceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "[{}] {} ({:?}, {:04x}:{:04x})", self.index, self.name, self.device_type, self.vendor_id, self.device_id)?;
+        writeln!(f, "    api {}, driver {:#x} {}", self.api_version, self.driver_version, self.driver_info.as_deref().unwrap_or(""))?;
+        writeln!(f, "    device local memory: {} MiB", self.device_local_memory / (1024 * 1024))?;
+        writeln!(f, "    max image 2d: {}, max push constants: {}, max bound sets: {}", self.max_image_dimension2_d, self.max_push_constants_size, self.max_bound_descriptor_sets)?;
+        writeln!(f, "    max uniform range: {}, max storage range: {}", self.max_uniform_buffer_range, self.max_storage_buffer_range)?;
+        writeln!(f, "    compute groups: {:?}, group size: {:?}, invocations: {}", self.max_compute_work_group_count, self.max_compute_work_group_size, self.max_compute_work_group_invocations)?;
+        writeln!(f, "    max anisotropy: {}", self.max_sampler_anisotropy)?;
+        for family in &self.queue_families {
+            writeln!(f, "    queue family {}: {} x {:?}", family.index, family.queue_count, family.queue_flags)?;
+        }
+        write!(f, "    extensions: {}", self.extensions.join(", "))
+    }
+}
+
+pub fn list_physical_devices(instance: Arc<Instance>) -> Vec<PhysicalDeviceReport> {
+    instance
+        .enumerate_physical_devices()
+        .expect("Failed to enumerate physical devices")
+        .enumerate()
+        .map(|(i, p)| PhysicalDeviceReport::new(i, p))
+        .collect()
+}
+
+// Queues handed out by create_device
+// compute and transfer are separate queues when the device has dedicated families for them,
+// otherwise they are the graphics queue
+#[derive(Clone)]
+pub struct Queues {
+    pub graphics: Arc<Queue>,
+    pub compute: Arc<Queue>,
+    pub transfer: Arc<Queue>,
+}
+
+impl Queues {
+    // Distinct queue family indices in use, for creating resources shared between the queues
+    pub fn family_indices(&self) -> Vec<u32> {
+        let mut indices = vec![self.graphics.queue_family_index()];
+        for queue in [&self.compute, &self.transfer] {
+            if !indices.contains(&queue.queue_family_index()) {
+                indices.push(queue.queue_family_index());
+            }
+        }
+        indices
+    }
+}
+
+// Async compute: a compute family without graphics
+fn find_compute_family(physical_device: &PhysicalDevice, graphics_family: u32) -> Option<u32> {
+    physical_device.queue_family_properties().iter()
+        .position(|q| q.queue_flags.contains(QueueFlags::COMPUTE) && !q.queue_flags.intersects(QueueFlags::GRAPHICS))
+        .map(|i| i as u32)
+        .filter(|i| *i != graphics_family)
+}
+
+// Dedicated transfer (DMA) family: transfer without graphics or compute
+fn find_transfer_family(physical_device: &PhysicalDevice, graphics_family: u32) -> Option<u32> {
+    physical_device.queue_family_properties().iter()
+        .position(|q| q.queue_flags.contains(QueueFlags::TRANSFER) && !q.queue_flags.intersects(QueueFlags::GRAPHICS | QueueFlags::COMPUTE))
+        .map(|i| i as u32)
+        .filter(|i| *i != graphics_family)
+}
+
+// Creates a device and its queues
+// The device is picked by the selection (overridden by RENGINE_DEVICE if set), among devices with the required features and extensions
+// and a queue family that supports graphics and presenting to the surface
+// Takes the first queue in the first queue family that supports graphics, plus one queue from the
+// dedicated compute and transfer families if the device has them
+pub fn create_device(instance: Arc<Instance>, requirements: &DeviceRequirements, surface: Arc<Surface>, selection: &DeviceSelection) -> (Arc<Device>, Queues, Arc<PhysicalDevice>) {
+    let selection = DeviceSelection::from_env().unwrap_or_else(|| selection.clone());
     let (physical_device, queue_family_index) =  instance
         .enumerate_physical_devices()
         .expect("Failed to enumerate physical devices")
-        // Filter by extensions
-        .filter(|p| p.supported_extensions().contains(&device_extensions))
+        .enumerate()
+        .filter(|(i, p)| selection.matches(*i, p))
+        .map(|(_, p)| p)
+        // Filter by required extensions and features
+        .filter(|p| requirements.is_supported_by(p))
         // Filter by queue family support
         .filter_map(|p| {
             p.queue_family_properties()
@@ -34,21 +335,100 @@ pub fn create_device(instance: Arc<Instance>, device_extensions: DeviceExtension
             PhysicalDeviceType::Cpu => 3,
             _ => 4,
         })
-        .expect("No device found");
+        .unwrap_or_else(|| {
+            let devices = list_physical_devices(instance.clone()).iter()
+                .map(|report| format!("\n  [{}] {} ({:?})", report.index, report.name, report.device_type))
+                .collect::<String>();
+            panic!("No device found for selection {:?}, available devices:{}", selection, devices)
+        });
+
+    log::info!("Using device: {} ({:?})", physical_device.properties().device_name, physical_device.properties().device_type);
+
+    let compute_family_index = find_compute_family(&physical_device, queue_family_index);
+    let transfer_family_index = find_transfer_family(&physical_device, queue_family_index);
+    let queue_family_indices = [Some(queue_family_index), compute_family_index, transfer_family_index];
 
-    let (device, mut queues) = Device::new(
+    let (device, queues) = Device::new(
         physical_device.clone(),
         DeviceCreateInfo{
-            queue_create_infos: vec![QueueCreateInfo{
-                queue_family_index,
+            queue_create_infos: queue_family_indices.iter().flatten().map(|queue_family_index| QueueCreateInfo{
+                queue_family_index: *queue_family_index,
                 ..Default::default()
-            }],
-            enabled_extensions: device_extensions,
+            }).collect(),
+            enabled_extensions: requirements.extensions_for(&physical_device),
+            enabled_features: requirements.features_for(&physical_device),
             ..Default::default()
         }
     ).expect("Failed to create device");
 
-    let queue = queues.next().expect("Failed to get queue");
+    // Queues come back in the order of queue_create_infos
+    let mut queues = queues.collect::<Vec<_>>().into_iter();
+    let graphics = queues.next().expect("Failed to get queue");
+    let compute = compute_family_index.map(|_| queues.next().expect("Failed to get compute queue")).unwrap_or_else(|| graphics.clone());
+    let transfer = transfer_family_index.map(|_| queues.next().expect("Failed to get transfer queue")).unwrap_or_else(|| graphics.clone());
+
+    (device, Queues { graphics, compute, transfer }, physical_device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh_shader_requirements() -> DeviceRequirements {
+        DeviceRequirements {
+            optional_extensions: DeviceExtensions { khr_swapchain: true, ..DeviceExtensions::empty() },
+            optional_features: Features { mesh_shader: true, fill_mode_non_solid: true, ..Features::empty() },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn optional_features_are_limited_to_supported_ones() {
+        let requirements = mesh_shader_requirements();
+        let supported_extensions = DeviceExtensions { ext_mesh_shader: true, ..DeviceExtensions::empty() };
+        let supported_features = Features { fill_mode_non_solid: true, ..Features::empty() };
+
+        let features = requirements.enabled_features(&supported_extensions, &supported_features);
+        assert_eq!(features, supported_features);
+        let extensions = requirements.enabled_extensions(&supported_extensions, &supported_features);
+        assert_eq!(extensions, DeviceExtensions::empty());
+    }
+
+    #[test]
+    fn enabled_optional_features_bring_their_extensions() {
+        let requirements = mesh_shader_requirements();
+        let supported_extensions = DeviceExtensions { khr_swapchain: true, ext_mesh_shader: true, ..DeviceExtensions::empty() };
+        let supported_features = Features { mesh_shader: true, fill_mode_non_solid: true, ..Features::empty() };
+
+        let features = requirements.enabled_features(&supported_extensions, &supported_features);
+        assert_eq!(features, supported_features);
+        let extensions = requirements.enabled_extensions(&supported_extensions, &supported_features);
+        assert_eq!(extensions, supported_extensions);
+    }
+
+    #[test]
+    fn optional_features_without_their_extensions_are_dropped() {
+        let requirements = mesh_shader_requirements();
+        let supported_extensions = DeviceExtensions { khr_swapchain: true, ..DeviceExtensions::empty() };
+        let supported_features = Features { mesh_shader: true, fill_mode_non_solid: true, ..Features::empty() };
+
+        let features = requirements.enabled_features(&supported_extensions, &supported_features);
+        assert_eq!(features, Features { fill_mode_non_solid: true, ..Features::empty() });
+        let extensions = requirements.enabled_extensions(&supported_extensions, &supported_features);
+        assert_eq!(extensions, supported_extensions);
+    }
+
+    #[test]
+    fn required_features_keep_their_extensions() {
+        let requirements = DeviceRequirements {
+            required_features: Features { ray_query: true, ..Features::empty() },
+            ..Default::default()
+        };
+        let supported_extensions = DeviceExtensions { khr_ray_query: true, ..DeviceExtensions::empty() };
+        let supported_features = Features { ray_query: true, ..Features::empty() };
 
-    (device, queue, queue_family_index, physical_device)
-}
\ No newline at end of file
+        let extensions = requirements.enabled_extensions(&supported_extensions, &supported_features);
+        assert_eq!(extensions, supported_extensions);
+        assert_eq!(requirements.extensions_needed_by(&requirements.required_features), supported_extensions);
+    }
+}
@@ -0,0 +1,107 @@
+use vulkano::device::Device;
+use vulkano::sync::{self, GpuFuture};
+use vulkano::sync::future::FenceSignalFuture;
+use std::sync::Arc;
+
+pub type FrameFence = Arc<FenceSignalFuture<Box<dyn GpuFuture>>>;
+
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+// Tracks the frames the CPU is allowed to record ahead of the GPU
+// Frame slots are independent from swapchain images, a slot is only reused once its fence has signalled
+pub struct FramesInFlight {
+    fences: Vec<Option<FrameFence>>,
+    // Frame slot that last rendered to each swapchain image
+    image_frames: Vec<Option<usize>>,
+    current: usize,
+    previous: Option<usize>,
+}
+
+impl FramesInFlight {
+    pub fn new(count: usize, swapchain_image_count: usize) -> Self {
+        assert!(count > 0, "Need at least one frame in flight");
+        FramesInFlight {
+            fences: vec![None; count],
+            image_frames: vec![None; swapchain_image_count],
+            current: 0,
+            previous: None,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.fences.len()
+    }
+
+    // Slot of the frame being recorded, use it to index PerFrame resources
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    // Blocks until the current slot's previous submission, and whichever frame last used the image, are done
+    // Returns the future the new submission should start after
+    pub fn begin(&mut self, device: Arc<Device>, image_idx: u32) -> Box<dyn GpuFuture> {
+        if let Some(fence) = self.fences[self.current].take() {
+            fence.wait(None).unwrap();
+        }
+        if let Some(frame) = self.image_frames[image_idx as usize] {
+            if let Some(fence) = &self.fences[frame] {
+                fence.wait(None).unwrap();
+            }
+        }
+        self.image_frames[image_idx as usize] = Some(self.current);
+
+        match self.previous.and_then(|previous| self.fences[previous].clone()) {
+            Some(fence) => Box::new(fence) as Box<dyn GpuFuture>,
+            None => {
+                let mut now = sync::now(device);
+                now.cleanup_finished();
+                Box::new(now) as Box<dyn GpuFuture>
+            }
+        }
+    }
+
+    // Stores the submission's fence (None if it failed) and moves to the next slot
+    pub fn end(&mut self, fence: Option<FrameFence>) {
+        self.fences[self.current] = fence;
+        self.previous = Some(self.current);
+        self.current = (self.current + 1) % self.fences.len();
+    }
+
+    // Waits for every frame, i.e. before destroying resources the GPU may still use
+    pub fn wait_idle(&mut self) {
+        for fence in self.fences.iter_mut() {
+            if let Some(fence) = fence.take() {
+                fence.wait(None).unwrap();
+            }
+        }
+        self.previous = None;
+    }
+
+    // The swapchain image count can change when the swapchain is recreated
+    pub fn reset_images(&mut self, swapchain_image_count: usize) {
+        self.image_frames = vec![None; swapchain_image_count];
+    }
+}
+
+// One copy of a resource per frame in flight, i.e. uniform buffers written by the CPU every frame
+pub struct PerFrame<T> {
+    items: Vec<T>,
+}
+
+impl<T> PerFrame<T> {
+    pub fn new(frames: &FramesInFlight, create: impl FnMut(usize) -> T) -> Self {
+        PerFrame { items: (0..frames.count()).map(create).collect() }
+    }
+
+    pub fn current(&self, frames: &FramesInFlight) -> &T {
+        &self.items[frames.current()]
+    }
+
+    pub fn current_mut(&mut self, frames: &FramesInFlight) -> &mut T {
+        &mut self.items[frames.current()]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+}
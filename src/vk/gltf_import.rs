@@ -0,0 +1,572 @@
+use glam::{Mat4, Quat, Vec3};
+use vulkano::device::Queue;
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+use vulkano::format::Format;
+use vulkano::image::sampler::{Sampler, SamplerCreateInfo, Filter, SamplerAddressMode, SamplerMipmapMode};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::vk::camera::Projection;
+use crate::vk::image::{self, Texture};
+use crate::vk::mesh::{self, Mesh, Submesh};
+use crate::vk::scene::LightKind;
+use crate::vk::Vert;
+
+// Far plane used for glTF cameras with an infinite projection
+const INFINITE_FAR_PLANE: f32 = 10000.0;
+
+#[derive(Clone, Debug, Default)]
+pub struct GltfImportOptions {
+    pub skins: bool,
+    pub animations: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    Opaque,
+    // Fully transparent below the cutoff, opaque above it
+    Mask,
+    Blend,
+}
+
+// Metallic-roughness material, textures index into GltfScene::textures
+#[derive(Clone, Debug)]
+pub struct GltfMaterial {
+    pub name: String,
+    pub base_color_factor: [f32; 4],
+    pub base_color_texture: Option<usize>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    // Metalness in blue, roughness in green
+    pub metallic_roughness_texture: Option<usize>,
+    pub normal_texture: Option<usize>,
+    pub normal_scale: f32,
+    pub occlusion_texture: Option<usize>,
+    pub occlusion_strength: f32,
+    pub emissive_factor: [f32; 3],
+    pub emissive_texture: Option<usize>,
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32,
+    pub double_sided: bool,
+}
+
+impl Default for GltfMaterial {
+    // The glTF default material
+    fn default() -> Self {
+        GltfMaterial {
+            name: "default".to_string(),
+            base_color_factor: [1.0; 4],
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            occlusion_texture: None,
+            occlusion_strength: 1.0,
+            emissive_factor: [0.0; 3],
+            emissive_texture: None,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            double_sided: false,
+        }
+    }
+}
+
+// One submesh per primitive, material slots index into GltfScene::materials
+// Attributes that don't fit in Vert are kept on the CPU, one entry per vertex, None when no primitive had them
+pub struct GltfMesh {
+    pub mesh: Arc<Mesh>,
+    pub colors: Option<Vec<[f32; 4]>>,
+    pub uvs1: Option<Vec<[f32; 2]>>,
+    pub joints: Option<Vec<[u16; 4]>>,
+    pub weights: Option<Vec<[f32; 4]>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GltfNode {
+    pub name: Option<String>,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+    pub mesh: Option<usize>,
+    pub camera: Option<usize>,
+    pub light: Option<usize>,
+    pub skin: Option<usize>,
+}
+
+impl GltfNode {
+    pub fn local_transform(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GltfCamera {
+    pub name: Option<String>,
+    pub projection: Projection,
+    // None means the viewport's aspect ratio
+    pub aspect: Option<f32>,
+}
+
+// KHR_lights_punctual, lights point down the node's -Z
+#[derive(Clone, Debug)]
+pub struct GltfLight {
+    pub name: Option<String>,
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    // Lux for directional lights, candela for the others
+    pub intensity: f32,
+    pub range: Option<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GltfSkin {
+    pub name: Option<String>,
+    // Node indices
+    pub joints: Vec<usize>,
+    pub inverse_bind_matrices: Vec<Mat4>,
+    pub skeleton: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Linear,
+    Step,
+    // Every keyframe has an in tangent, a value and an out tangent, in that order
+    CubicSpline,
+}
+
+#[derive(Clone, Debug)]
+pub enum ChannelValues {
+    Translations(Vec<Vec3>),
+    Rotations(Vec<Quat>),
+    Scales(Vec<Vec3>),
+    // Morph target weights of every keyframe, flattened
+    Weights(Vec<f32>),
+}
+
+#[derive(Clone, Debug)]
+pub struct GltfChannel {
+    pub node: usize,
+    pub interpolation: Interpolation,
+    // Keyframe times in seconds
+    pub times: Vec<f32>,
+    pub values: ChannelValues,
+}
+
+#[derive(Clone, Debug)]
+pub struct GltfAnimation {
+    pub name: Option<String>,
+    pub channels: Vec<GltfChannel>,
+    pub duration: f32,
+}
+
+pub struct GltfScene {
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<GltfMaterial>,
+    pub textures: Vec<Texture>,
+    // Every node of the file, roots are the nodes of the default scene
+    pub nodes: Vec<GltfNode>,
+    pub roots: Vec<usize>,
+    pub cameras: Vec<GltfCamera>,
+    pub lights: Vec<GltfLight>,
+    pub skins: Vec<GltfSkin>,
+    pub animations: Vec<GltfAnimation>,
+}
+
+impl GltfScene {
+    pub fn world_transform(&self, node: usize) -> Mat4 {
+        let mut transform = self.nodes[node].local_transform();
+        let mut parent = self.nodes[node].parent;
+        while let Some(i) = parent {
+            transform = self.nodes[i].local_transform() * transform;
+            parent = self.nodes[i].parent;
+        }
+        transform
+    }
+}
+
+// Uploads glTF textures on first use, base color and emissive textures are sampled as sRGB, the rest as linear
+struct TextureLoader<'a> {
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    queue: Arc<Queue>,
+    images: &'a [::gltf::image::Data],
+    textures: Vec<Texture>,
+    // (glTF texture index, srgb) -> index in textures
+    loaded: HashMap<(usize, bool), usize>,
+}
+
+impl<'a> TextureLoader<'a> {
+    fn load(&mut self, texture: ::gltf::Texture, srgb: bool) -> Option<usize> {
+        if let Some(&i) = self.loaded.get(&(texture.index(), srgb)) {
+            return Some(i);
+        }
+        let data = &self.images[texture.source().index()];
+        let pixels = match rgba8_pixels(data) {
+            Some(pixels) => pixels,
+            None => {
+                log::warn!("Skipping texture {}, {:?} images are not supported", texture.index(), data.format);
+                return None;
+            }
+        };
+        let format = if srgb { Format::R8G8B8A8_SRGB } else { Format::R8G8B8A8_UNORM };
+        let view = image::create_texture(
+            self.memory_allocator.clone(),
+            self.command_buffer_allocator.clone(),
+            self.queue.clone(),
+            format,
+            [data.width, data.height],
+            &pixels
+        );
+        let sampler = create_sampler(self.queue.clone(), texture.sampler());
+        self.textures.push(Texture { view, sampler });
+        self.loaded.insert((texture.index(), srgb), self.textures.len() - 1);
+        Some(self.textures.len() - 1)
+    }
+}
+
+// Mipmaps aren't generated, so the mipmap part of minification filters is ignored
+fn create_sampler(queue: Arc<Queue>, sampler: ::gltf::texture::Sampler) -> Arc<Sampler> {
+    use ::gltf::texture::{MagFilter, MinFilter, WrappingMode};
+    let address_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => SamplerAddressMode::ClampToEdge,
+        WrappingMode::MirroredRepeat => SamplerAddressMode::MirroredRepeat,
+        WrappingMode::Repeat => SamplerAddressMode::Repeat,
+    };
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => Filter::Nearest,
+        _ => Filter::Linear,
+    };
+    let min_filter = match sampler.min_filter() {
+        Some(MinFilter::Nearest | MinFilter::NearestMipmapNearest | MinFilter::NearestMipmapLinear) => Filter::Nearest,
+        _ => Filter::Linear,
+    };
+    Sampler::new(queue.device().clone(), SamplerCreateInfo {
+        mag_filter,
+        min_filter,
+        mipmap_mode: SamplerMipmapMode::Linear,
+        address_mode: [address_mode(sampler.wrap_s()), address_mode(sampler.wrap_t()), SamplerAddressMode::Repeat],
+        ..Default::default()
+    }).expect("Failed to create sampler")
+}
+
+// Expands 8 and 16 bit images to RGBA8, grey and grey-alpha images are replicated into RGB
+fn rgba8_pixels(data: &::gltf::image::Data) -> Option<Vec<u8>> {
+    use ::gltf::image::Format as ImageFormat;
+    let (channels, bytes_per_channel) = match data.format {
+        ImageFormat::R8 => (1, 1),
+        ImageFormat::R8G8 => (2, 1),
+        ImageFormat::R8G8B8 => (3, 1),
+        ImageFormat::R8G8B8A8 => (4, 1),
+        ImageFormat::R16 => (1, 2),
+        ImageFormat::R16G16 => (2, 2),
+        ImageFormat::R16G16B16 => (3, 2),
+        ImageFormat::R16G16B16A16 => (4, 2),
+        _ => return None,
+    };
+    // 16 bit channels are little endian, keep the high byte
+    let channel = |pixel: &[u8], i: usize| pixel[i * bytes_per_channel + bytes_per_channel - 1];
+    let pixels = data.pixels
+        .chunks_exact(channels * bytes_per_channel)
+        .flat_map(|pixel| match channels {
+            1 => [channel(pixel, 0), channel(pixel, 0), channel(pixel, 0), 255],
+            2 => [channel(pixel, 0), channel(pixel, 0), channel(pixel, 0), channel(pixel, 1)],
+            3 => [channel(pixel, 0), channel(pixel, 1), channel(pixel, 2), 255],
+            _ => [channel(pixel, 0), channel(pixel, 1), channel(pixel, 2), channel(pixel, 3)],
+        })
+        .collect();
+    Some(pixels)
+}
+
+fn load_material(material: ::gltf::Material, textures: &mut TextureLoader) -> GltfMaterial {
+    let pbr = material.pbr_metallic_roughness();
+    GltfMaterial {
+        name: material.name().map_or_else(|| format!("material {}", material.index().unwrap_or(0)), str::to_string),
+        base_color_factor: pbr.base_color_factor(),
+        base_color_texture: pbr.base_color_texture().and_then(|info| textures.load(info.texture(), true)),
+        metallic_factor: pbr.metallic_factor(),
+        roughness_factor: pbr.roughness_factor(),
+        metallic_roughness_texture: pbr.metallic_roughness_texture().and_then(|info| textures.load(info.texture(), false)),
+        normal_texture: material.normal_texture().and_then(|normal| textures.load(normal.texture(), false)),
+        normal_scale: material.normal_texture().map_or(1.0, |normal| normal.scale()),
+        occlusion_texture: material.occlusion_texture().and_then(|occlusion| textures.load(occlusion.texture(), false)),
+        occlusion_strength: material.occlusion_texture().map_or(1.0, |occlusion| occlusion.strength()),
+        emissive_factor: material.emissive_factor(),
+        emissive_texture: material.emissive_texture().and_then(|info| textures.load(info.texture(), true)),
+        alpha_mode: match material.alpha_mode() {
+            ::gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+            ::gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+            ::gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+        },
+        alpha_cutoff: material.alpha_cutoff().unwrap_or(0.5),
+        double_sided: material.double_sided(),
+    }
+}
+
+// Primitives that aren't triangle lists are skipped, missing normals and tangents are computed
+fn load_mesh(
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    mesh: ::gltf::Mesh,
+    buffers: &[::gltf::buffer::Data],
+    default_material: usize
+) -> Option<GltfMesh> {
+    let name = mesh.name().map_or_else(|| format!("mesh {}", mesh.index()), str::to_string);
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut submeshes = Vec::new();
+    let mut colors = Vec::new();
+    let mut uvs1 = Vec::new();
+    let mut joints = Vec::new();
+    let mut weights = Vec::new();
+    let (mut has_colors, mut has_uvs1, mut has_skin) = (false, false, false);
+
+    for primitive in mesh.primitives() {
+        if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+            log::warn!("Skipping {:?} primitive of `{}`, only triangles are supported", primitive.mode(), name);
+            continue;
+        }
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()][..]));
+        let positions: Vec<[f32; 3]> = match reader.read_positions() {
+            Some(positions) => positions.collect(),
+            None => continue,
+        };
+        let first_vertex = vertices.len();
+        let first_index = indices.len() as u32;
+        let count = positions.len();
+        let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|normals| normals.collect());
+        let tangents: Option<Vec<[f32; 4]>> = reader.read_tangents().map(|tangents| tangents.collect());
+        let uvs: Option<Vec<[f32; 2]>> = reader.read_tex_coords(0).map(|uvs| uvs.into_f32().collect());
+        let local_indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..count as u32).collect(),
+        };
+        for i in 0..count {
+            vertices.push(Vert {
+                position: positions[i],
+                normal: normals.as_ref().map_or([0.0; 3], |normals| normals[i]),
+                uv: uvs.as_ref().map_or([0.0; 2], |uvs| uvs[i]),
+                tangent: tangents.as_ref().map_or([0.0; 4], |tangents| tangents[i]),
+            });
+        }
+        if normals.is_none() {
+            mesh::compute_normals(&mut vertices[first_vertex..], &local_indices);
+        }
+        if tangents.is_none() {
+            mesh::compute_tangents(&mut vertices[first_vertex..], &local_indices);
+        }
+        indices.extend(local_indices.iter().map(|index| index + first_vertex as u32));
+
+        match reader.read_colors(0) {
+            Some(read) => { has_colors = true; colors.extend(read.into_rgba_f32()); }
+            None => colors.extend(std::iter::repeat_n([1.0; 4], count)),
+        }
+        match reader.read_tex_coords(1) {
+            Some(read) => { has_uvs1 = true; uvs1.extend(read.into_f32()); }
+            None => uvs1.extend(std::iter::repeat_n([0.0; 2], count)),
+        }
+        match (reader.read_joints(0), reader.read_weights(0)) {
+            (Some(read_joints), Some(read_weights)) => {
+                has_skin = true;
+                joints.extend(read_joints.into_u16());
+                weights.extend(read_weights.into_f32());
+            }
+            _ => {
+                joints.extend(std::iter::repeat_n([0; 4], count));
+                weights.extend(std::iter::repeat_n([0.0; 4], count));
+            }
+        }
+
+        let material_slot = primitive.material().index().unwrap_or(default_material);
+        submeshes.push(Submesh::new(first_index..indices.len() as u32, material_slot));
+    }
+    if submeshes.is_empty() {
+        log::warn!("Mesh `{}` has no triangles", name);
+        return None;
+    }
+
+    Some(GltfMesh {
+        mesh: Mesh::new(memory_allocator, &name, &vertices, &indices, submeshes),
+        colors: has_colors.then_some(colors),
+        uvs1: has_uvs1.then_some(uvs1),
+        joints: has_skin.then_some(joints),
+        weights: has_skin.then_some(weights),
+    })
+}
+
+fn load_camera(camera: ::gltf::Camera) -> GltfCamera {
+    let (projection, aspect) = match camera.projection() {
+        ::gltf::camera::Projection::Perspective(perspective) => (
+            Projection::Perspective {
+                fov_y: perspective.yfov(),
+                near: perspective.znear(),
+                far: perspective.zfar().unwrap_or(INFINITE_FAR_PLANE),
+            },
+            perspective.aspect_ratio(),
+        ),
+        ::gltf::camera::Projection::Orthographic(orthographic) => (
+            Projection::Orthographic {
+                height: orthographic.ymag() * 2.0,
+                near: orthographic.znear(),
+                far: orthographic.zfar(),
+            },
+            Some(orthographic.xmag() / orthographic.ymag()),
+        ),
+    };
+    GltfCamera { name: camera.name().map(str::to_string), projection, aspect }
+}
+
+fn load_light(light: ::gltf::khr_lights_punctual::Light) -> GltfLight {
+    use ::gltf::khr_lights_punctual::Kind;
+    GltfLight {
+        name: light.name().map(str::to_string),
+        kind: match light.kind() {
+            Kind::Directional => LightKind::Directional,
+            Kind::Point => LightKind::Point,
+            Kind::Spot { inner_cone_angle, outer_cone_angle } => LightKind::Spot { inner_cone_angle, outer_cone_angle },
+        },
+        color: light.color(),
+        intensity: light.intensity(),
+        range: light.range(),
+    }
+}
+
+fn load_skin(skin: ::gltf::Skin, buffers: &[::gltf::buffer::Data]) -> GltfSkin {
+    let joints: Vec<usize> = skin.joints().map(|joint| joint.index()).collect();
+    let reader = skin.reader(|buffer| Some(&buffers[buffer.index()][..]));
+    let inverse_bind_matrices = match reader.read_inverse_bind_matrices() {
+        Some(matrices) => matrices.map(|matrix| Mat4::from_cols_array_2d(&matrix)).collect(),
+        None => vec![Mat4::IDENTITY; joints.len()],
+    };
+    GltfSkin {
+        name: skin.name().map(str::to_string),
+        joints,
+        inverse_bind_matrices,
+        skeleton: skin.skeleton().map(|node| node.index()),
+    }
+}
+
+fn load_animation(animation: ::gltf::Animation, buffers: &[::gltf::buffer::Data]) -> GltfAnimation {
+    use ::gltf::animation::util::ReadOutputs;
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let reader = channel.reader(|buffer| Some(&buffers[buffer.index()][..]));
+        let (times, outputs) = match (reader.read_inputs(), reader.read_outputs()) {
+            (Some(times), Some(outputs)) => (times.collect(), outputs),
+            _ => continue,
+        };
+        let values = match outputs {
+            ReadOutputs::Translations(translations) => ChannelValues::Translations(translations.map(Vec3::from).collect()),
+            ReadOutputs::Rotations(rotations) => ChannelValues::Rotations(rotations.into_f32().map(Quat::from_array).collect()),
+            ReadOutputs::Scales(scales) => ChannelValues::Scales(scales.map(Vec3::from).collect()),
+            ReadOutputs::MorphTargetWeights(weights) => ChannelValues::Weights(weights.into_f32().collect()),
+        };
+        channels.push(GltfChannel {
+            node: channel.target().node().index(),
+            interpolation: match channel.sampler().interpolation() {
+                ::gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                ::gltf::animation::Interpolation::Step => Interpolation::Step,
+                ::gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            },
+            times,
+            values,
+        });
+    }
+    let duration = channels.iter()
+        .filter_map(|channel| channel.times.last().copied())
+        .fold(0.0, f32::max);
+    GltfAnimation { name: animation.name().map(str::to_string), channels, duration }
+}
+
+// Loads .gltf and .glb files, buffers and images can be embedded or external
+// Textures are uploaded through the queue, waiting for each upload to finish
+pub fn load_gltf(
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    queue: Arc<Queue>,
+    path: impl AsRef<Path>,
+    options: &GltfImportOptions
+) -> GltfScene {
+    let path = path.as_ref();
+    let (document, buffers, images) = ::gltf::import(path)
+        .unwrap_or_else(|e| panic!("Failed to import glTF `{}`: {}", path.display(), e));
+
+    let mut textures = TextureLoader {
+        memory_allocator: memory_allocator.clone(),
+        command_buffer_allocator,
+        queue,
+        images: &images,
+        textures: Vec::new(),
+        loaded: HashMap::new(),
+    };
+    let mut materials: Vec<GltfMaterial> = document.materials()
+        .map(|material| load_material(material, &mut textures))
+        .collect();
+    // Primitives without a material use the default one, appended after the file's materials
+    let default_material = materials.len();
+    materials.push(GltfMaterial::default());
+
+    // Meshes without triangles are dropped, so node mesh indices are remapped
+    let mut mesh_indices = HashMap::new();
+    let mut meshes = Vec::new();
+    for mesh in document.meshes() {
+        let index = mesh.index();
+        if let Some(mesh) = load_mesh(memory_allocator.clone(), mesh, &buffers, default_material) {
+            mesh_indices.insert(index, meshes.len());
+            meshes.push(mesh);
+        }
+    }
+
+    let mut nodes: Vec<GltfNode> = document.nodes().map(|node| {
+        let (translation, rotation, scale) = node.transform().decomposed();
+        GltfNode {
+            name: node.name().map(str::to_string),
+            parent: None,
+            children: node.children().map(|child| child.index()).collect(),
+            translation: Vec3::from(translation),
+            rotation: Quat::from_array(rotation),
+            scale: Vec3::from(scale),
+            mesh: node.mesh().and_then(|mesh| mesh_indices.get(&mesh.index()).copied()),
+            camera: node.camera().map(|camera| camera.index()),
+            light: node.light().map(|light| light.index()),
+            skin: if options.skins { node.skin().map(|skin| skin.index()) } else { None },
+        }
+    }).collect();
+    for i in 0..nodes.len() {
+        for child in nodes[i].children.clone() {
+            nodes[child].parent = Some(i);
+        }
+    }
+    let roots = match document.default_scene().or_else(|| document.scenes().next()) {
+        Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+        None => (0..nodes.len()).filter(|&i| nodes[i].parent.is_none()).collect(),
+    };
+
+    let skins = if options.skins {
+        document.skins().map(|skin| load_skin(skin, &buffers)).collect()
+    } else {
+        Vec::new()
+    };
+    let animations = if options.animations {
+        document.animations().map(|animation| load_animation(animation, &buffers)).collect()
+    } else {
+        Vec::new()
+    };
+
+    GltfScene {
+        meshes,
+        materials,
+        textures: textures.textures,
+        nodes,
+        roots,
+        cameras: document.cameras().map(load_camera).collect(),
+        lights: document.lights().map_or_else(Vec::new, |lights| lights.map(load_light).collect()),
+        skins,
+        animations,
+    }
+}
@@ -1,25 +1,29 @@
 use vulkano::image::{Image, ImageCreateInfo, ImageType, ImageUsage};
 use vulkano::image::view::{ImageView, ImageViewCreateInfo};
-use vulkano::format::{Format, ClearColorValue};
+use vulkano::image::sampler::Sampler;
+use vulkano::format::{Format, FormatFeatures, ClearColorValue};
 use vulkano::memory::allocator::{
     StandardMemoryAllocator,
     AllocationCreateInfo,
     MemoryTypeFilter,
 };
-use vulkano::command_buffer::ClearColorImageInfo;
+use vulkano::command_buffer::{ClearColorImageInfo, CopyBufferToImageInfo};
+use vulkano::buffer::BufferUsage;
 use vulkano::swapchain::{self, SwapchainAcquireFuture};
-use vulkano::swapchain::{Swapchain, SwapchainCreateInfo, SwapchainPresentInfo, Surface, CompositeAlphas};
+use vulkano::swapchain::{Swapchain, SwapchainCreateInfo, SwapchainPresentInfo, Surface, SurfaceCapabilities, CompositeAlpha, PresentMode, ColorSpace};
 use vulkano::{Validated, VulkanError};
 use vulkano::device::Device;
 use vulkano::device::physical::PhysicalDevice; 
 use vulkano::device::Queue;
 use vulkano::sync::{self, GpuFuture};
 use vulkano::sync::future::FenceSignalFuture;
-use vulkano::command_buffer::{PrimaryAutoCommandBuffer};
+use vulkano::command_buffer::{PrimaryAutoCommandBuffer, CommandBufferExecError};
 use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
 use std::sync::Arc;
-use crate::vk::buffer::PrimaryCommandBufferBuilder;
+use crate::vk::buffer::{self, PrimaryCommandBufferBuilder};
+use crate::vk::frame::FrameFence;
 use winit::window::Window;
+use crate::vk::debug;
 pub fn create_image(memory_allocator: Arc<StandardMemoryAllocator>, format: Format, usage: ImageUsage, image_type: ImageType, dimensions: [u32; 3]) -> Arc<Image> {
     let image = Image::new(
         memory_allocator.clone(),
@@ -35,6 +39,7 @@ pub fn create_image(memory_allocator: Arc<StandardMemoryAllocator>, format: Form
             ..Default::default()
         }
     ).unwrap();
+    debug::set_object_name(&*image, &format!("{:?} image {}x{}x{}", format, dimensions[0], dimensions[1], dimensions[2]));
     image
 }
 
@@ -47,6 +52,58 @@ pub fn create_image_view(image: Arc<Image>, format: Format) -> Arc<ImageView> {
     ImageView::new_default(image).unwrap()
 }
 
+// Depth formats in order of preference, Vulkan guarantees D16_UNORM can be a sampled depth attachment
+const DEPTH_FORMATS: [Format; 3] = [Format::D32_SFLOAT, Format::X8_D24_UNORM_PACK32, Format::D16_UNORM];
+
+pub fn choose_depth_format(physical_device: &PhysicalDevice, required_features: FormatFeatures) -> Format {
+    DEPTH_FORMATS.into_iter()
+        .find(|&format| physical_device.format_properties(format)
+            .is_ok_and(|properties| properties.optimal_tiling_features.contains(required_features)))
+        .unwrap_or_else(|| panic!("The device has no depth format with {:?}", required_features))
+}
+
+// A sampled image with the sampler to read it with
+#[derive(Clone)]
+pub struct Texture {
+    pub view: Arc<ImageView>,
+    pub sampler: Arc<Sampler>,
+}
+
+// Uploads tightly packed pixels through a staging buffer and waits for the copy to finish
+pub fn create_texture(
+    memory_allocator: Arc<StandardMemoryAllocator>,
+    command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+    queue: Arc<Queue>,
+    format: Format,
+    dimensions: [u32; 2],
+    pixels: &[u8]
+) -> Arc<ImageView> {
+    let expected_size = format.block_size() * dimensions[0] as u64 * dimensions[1] as u64;
+    if pixels.len() as u64 != expected_size {
+        panic!("{:?} texture of {}x{} needs {} bytes, got {}", format, dimensions[0], dimensions[1], expected_size, pixels.len());
+    }
+    let staging_buffer = buffer::create_buffer_from_iter(
+        memory_allocator.clone(),
+        buffer::STAGING_BUFFER_MEMORY_TYPE_FILTER,
+        BufferUsage::TRANSFER_SRC,
+        pixels.iter().copied()
+    );
+    let image = create_image(
+        memory_allocator,
+        format,
+        ImageUsage::SAMPLED | ImageUsage::TRANSFER_DST,
+        ImageType::Dim2d,
+        [dimensions[0], dimensions[1], 1]
+    );
+    let mut builder = buffer::create_command_buffer_builder(command_buffer_allocator, queue.clone());
+    builder
+        .copy_buffer_to_image(CopyBufferToImageInfo::buffer_image(staging_buffer, image.clone()))
+        .unwrap();
+    let command_buffer = buffer::build_command_buffer(builder);
+    buffer::submit_execute_wait_fenced(queue.device().clone(), queue, command_buffer);
+    create_image_view(image.clone(), format)
+}
+
 // color needs to be in the range [0, 1], normalized to the format
 // i.e. R8G8B8A8_UNORM is [0, 255] -> [0.0, 1.0] for each channel
 pub fn clear_image(mut builder: PrimaryCommandBufferBuilder, image: Arc<Image>, color: [f32; 4]) -> PrimaryCommandBufferBuilder {
@@ -59,51 +116,217 @@ pub fn clear_image(mut builder: PrimaryCommandBufferBuilder, image: Arc<Image>,
     builder
 }
 
-pub fn create_swapchain(device: Arc<Device>, window: Arc<Window>, surface: Arc<Surface>, physical_device: Arc<PhysicalDevice>) -> (Arc<Swapchain>, Vec<Arc<Image>>) {
-    let surface_capabilities = physical_device.surface_capabilities(&surface, Default::default()).expect("Failed to get surface capabilities");
-    let image_extent = [window.inner_size().width, window.inner_size().height];
-    let image_usage = ImageUsage::COLOR_ATTACHMENT;
-    let image_format = physical_device.surface_formats(&surface, Default::default()).unwrap()[0].0;
-    let composite_alpha = surface_capabilities.supported_composite_alpha.into_iter().next().unwrap();
-    Swapchain::new(device, surface, SwapchainCreateInfo {
-        min_image_count: surface_capabilities.min_image_count + 1,
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresentPreference {
+    // Fifo, always available
+    Vsync,
+    // FifoRelaxed, tears instead of stalling when a frame is late
+    AdaptiveVsync,
+    // Mailbox, no tearing and no cap on the render rate
+    LowLatency,
+    // Immediate, vsync off for benchmarks
+    Uncapped,
+}
+
+impl PresentPreference {
+    // Modes to try in order, Fifo is guaranteed to be supported so it's always last
+    fn candidates(self) -> &'static [PresentMode] {
+        match self {
+            PresentPreference::Vsync => &[PresentMode::Fifo],
+            PresentPreference::AdaptiveVsync => &[PresentMode::FifoRelaxed, PresentMode::Fifo],
+            PresentPreference::LowLatency => &[PresentMode::Mailbox, PresentMode::Fifo],
+            PresentPreference::Uncapped => &[PresentMode::Immediate, PresentMode::Mailbox, PresentMode::Fifo],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorPreference {
+    // 8 bit sRGB format, writes from shaders are encoded to sRGB by the hardware
+    Srgb,
+    // 10 bit HDR10 (ST.2084 PQ), needs ext_swapchain_colorspace
+    Hdr10,
+    // 16 bit float scRGB, needs ext_swapchain_colorspace
+    ExtendedLinear,
+    // Whatever the surface reports first
+    Any,
+}
+
+impl ColorPreference {
+    fn candidates(self) -> &'static [(Format, ColorSpace)] {
+        match self {
+            ColorPreference::Srgb => &[
+                (Format::B8G8R8A8_SRGB, ColorSpace::SrgbNonLinear),
+                (Format::R8G8B8A8_SRGB, ColorSpace::SrgbNonLinear),
+                (Format::A8B8G8R8_SRGB_PACK32, ColorSpace::SrgbNonLinear),
+            ],
+            ColorPreference::Hdr10 => &[
+                (Format::A2B10G10R10_UNORM_PACK32, ColorSpace::Hdr10St2084),
+                (Format::A2R10G10B10_UNORM_PACK32, ColorSpace::Hdr10St2084),
+            ],
+            ColorPreference::ExtendedLinear => &[
+                (Format::R16G16B16A16_SFLOAT, ColorSpace::ExtendedSrgbLinear),
+            ],
+            ColorPreference::Any => &[],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SwapchainConfig {
+    pub present: PresentPreference,
+    pub color: ColorPreference,
+    // Clamped to what the surface supports, defaults to min_image_count + 1
+    pub image_count: Option<u32>,
+    // Blend with what's behind the window, set from WindowConfig::transparent
+    pub transparent: bool,
+}
+
+impl Default for SwapchainConfig {
+    fn default() -> Self {
+        SwapchainConfig {
+            present: PresentPreference::Vsync,
+            color: ColorPreference::Srgb,
+            image_count: None,
+            transparent: false,
+        }
+    }
+}
+
+// Falls back to sRGB and then to the first reported format when the preferred ones aren't supported
+fn choose_surface_format(physical_device: &PhysicalDevice, surface: &Surface, preference: ColorPreference) -> (Format, ColorSpace) {
+    let surface_formats = physical_device.surface_formats(surface, Default::default()).unwrap();
+    if preference == ColorPreference::Any {
+        return surface_formats[0];
+    }
+    preference.candidates().iter()
+        .chain(ColorPreference::Srgb.candidates())
+        .find(|candidate| surface_formats.contains(candidate))
+        .copied()
+        .unwrap_or_else(|| {
+            log::warn!("No sRGB surface format available, using {:?}", surface_formats[0]);
+            surface_formats[0]
+        })
+}
+
+fn choose_present_mode(physical_device: &PhysicalDevice, surface: &Surface, preference: PresentPreference) -> PresentMode {
+    let present_modes: Vec<PresentMode> = physical_device.surface_present_modes(surface, Default::default()).unwrap().collect();
+    preference.candidates().iter()
+        .find(|mode| present_modes.contains(mode))
+        .copied()
+        .unwrap_or(PresentMode::Fifo)
+}
+
+fn choose_image_count(surface_capabilities: &SurfaceCapabilities, image_count: Option<u32>) -> u32 {
+    let image_count = image_count.unwrap_or(surface_capabilities.min_image_count + 1).max(surface_capabilities.min_image_count);
+    match surface_capabilities.max_image_count {
+        Some(max_image_count) => image_count.min(max_image_count),
+        None => image_count,
+    }
+}
+
+fn choose_composite_alpha(surface_capabilities: &SurfaceCapabilities, transparent: bool) -> CompositeAlpha {
+    let supported = surface_capabilities.supported_composite_alpha;
+    let candidates: &[CompositeAlpha] = if transparent {
+        &[CompositeAlpha::PreMultiplied, CompositeAlpha::PostMultiplied, CompositeAlpha::Inherit]
+    } else {
+        &[CompositeAlpha::Opaque]
+    };
+    candidates.iter()
+        .find(|alpha| supported.contains_enum(**alpha))
+        .copied()
+        .unwrap_or_else(|| supported.into_iter().next().unwrap())
+}
+
+fn swapchain_create_info(physical_device: &PhysicalDevice, surface: &Surface, config: &SwapchainConfig, image_extent: [u32; 2]) -> SwapchainCreateInfo {
+    let surface_capabilities = physical_device.surface_capabilities(surface, Default::default()).expect("Failed to get surface capabilities");
+    let (image_format, image_color_space) = choose_surface_format(physical_device, surface, config.color);
+    let composite_alpha = choose_composite_alpha(&surface_capabilities, config.transparent);
+    SwapchainCreateInfo {
+        min_image_count: choose_image_count(&surface_capabilities, config.image_count),
         image_format,
-        image_extent: image_extent,
-        image_usage: image_usage,
+        image_color_space,
+        image_extent,
+        image_usage: ImageUsage::COLOR_ATTACHMENT,
         composite_alpha,
+        present_mode: choose_present_mode(physical_device, surface, config.present),
         ..Default::default()
-    }).unwrap()
+    }
 }
 
-pub fn recreate_swapchain(swapchain: Arc<Swapchain>, dimensions: [u32; 2]) -> (Arc<Swapchain>, Vec<Arc<Image>>) {
-    let (swapchain, swapchain_images) = swapchain.recreate(SwapchainCreateInfo {
-        image_extent: dimensions,
-        ..swapchain.create_info()
-     }).unwrap();
+pub fn create_swapchain(device: Arc<Device>, window: Arc<Window>, surface: Arc<Surface>, physical_device: Arc<PhysicalDevice>, config: &SwapchainConfig) -> (Arc<Swapchain>, Vec<Arc<Image>>) {
+    let image_extent = [window.inner_size().width, window.inner_size().height];
+    let create_info = swapchain_create_info(&physical_device, &surface, config, image_extent);
+    let (swapchain, swapchain_images) = Swapchain::new(device, surface, create_info).unwrap();
+    name_swapchain_images(&swapchain_images);
     (swapchain, swapchain_images)
 }
 
-pub fn obtain_next_swapchain_image(swapchain: Arc<Swapchain>) -> (Option<(u32, SwapchainAcquireFuture)>, bool) {
-    let (image_idx, suboptimal, acquire_future) = match swapchain::acquire_next_image(swapchain.clone(), None).map_err(Validated::unwrap){
-        Ok(result) => result,
-        Err(VulkanError::OutOfDate) => {
-            println!("Swapchain out of date");
-            return (None, true);
-        },
-        Err(e) => panic!("Failed to acquire swapchain image: {}", e),
-    };
+// Recreates the swapchain with new present mode, format and image count choices
+// The format can change, in which case render passes using the old format need recreating
+pub fn reconfigure_swapchain(swapchain: Arc<Swapchain>, physical_device: Arc<PhysicalDevice>, config: &SwapchainConfig, dimensions: [u32; 2]) -> (Arc<Swapchain>, Vec<Arc<Image>>) {
+    let create_info = swapchain_create_info(&physical_device, swapchain.surface(), config, dimensions);
+    let (swapchain, swapchain_images) = swapchain.recreate(create_info).unwrap();
+    name_swapchain_images(&swapchain_images);
+    (swapchain, swapchain_images)
+}
 
-    if suboptimal {
-        return (None, true);
+fn name_swapchain_images(images: &[Arc<Image>]) {
+    for (i, image) in images.iter().enumerate() {
+        debug::set_object_name(&**image, &format!("swapchain image {}", i));
     }
-    (Some((image_idx, acquire_future)), false)
 }
 
-pub fn present_swapchain_image(device: Arc<Device>, swapchain: Arc<Swapchain>, queue: Arc<Queue>, command_buffers: Vec<Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>>>, image_idx: u32, swapchain_image_future: SwapchainAcquireFuture) -> bool {
-    let execution_future = sync::now(device.clone())
+// What the swapchain needs after an acquire or present
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapchainStatus {
+    Ok,
+    // Suboptimal or out of date, recreate from the same surface
+    NeedsRecreate,
+    // The surface has to be created again before the swapchain
+    SurfaceLost,
+}
+
+impl SwapchainStatus {
+    // Keeps the most severe of the two
+    pub fn merge(self, other: SwapchainStatus) -> SwapchainStatus {
+        match (self, other) {
+            (SwapchainStatus::SurfaceLost, _) | (_, SwapchainStatus::SurfaceLost) => SwapchainStatus::SurfaceLost,
+            (SwapchainStatus::NeedsRecreate, _) | (_, SwapchainStatus::NeedsRecreate) => SwapchainStatus::NeedsRecreate,
+            _ => SwapchainStatus::Ok,
+        }
+    }
+}
+
+// Only errors about the swapchain or surface are recoverable, anything else (i.e. a lost device) panics
+fn status_from_error(error: VulkanError, action: &str) -> SwapchainStatus {
+    match error {
+        VulkanError::OutOfDate => SwapchainStatus::NeedsRecreate,
+        VulkanError::SurfaceLost => SwapchainStatus::SurfaceLost,
+        e => panic!("Failed to {}: {}", action, e),
+    }
+}
+
+// A suboptimal image is still returned so it can be presented, along with NeedsRecreate
+pub fn obtain_next_swapchain_image(swapchain: Arc<Swapchain>) -> (Option<(u32, SwapchainAcquireFuture)>, SwapchainStatus) {
+    match swapchain::acquire_next_image(swapchain.clone(), None).map_err(Validated::unwrap) {
+        Ok((image_idx, suboptimal, acquire_future)) => {
+            let status = if suboptimal { SwapchainStatus::NeedsRecreate } else { SwapchainStatus::Ok };
+            (Some((image_idx, acquire_future)), status)
+        },
+        Err(e) => (None, status_from_error(e, "acquire swapchain image")),
+    }
+}
+
+pub fn present_swapchain_image(device: Arc<Device>, swapchain: Arc<Swapchain>, queue: Arc<Queue>, command_buffers: Vec<Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>>>, image_idx: u32, swapchain_image_future: SwapchainAcquireFuture) -> SwapchainStatus {
+    let execution_future = match sync::now(device.clone())
         .join(swapchain_image_future)
         .then_execute(queue.clone(), command_buffers[image_idx as usize].clone())
-        .unwrap()
+    {
+        Ok(future) => future,
+        Err(e) => return submit_failed(e),
+    };
+    let execution_future = execution_future
         .then_swapchain_present(
             queue.clone(),
             SwapchainPresentInfo::swapchain_image_index(swapchain.clone(), image_idx)
@@ -113,30 +336,37 @@ pub fn present_swapchain_image(device: Arc<Device>, swapchain: Arc<Swapchain>, q
     match execution_future.map_err(Validated::unwrap) {
         Ok(future) => {
             future.wait(None).unwrap();
-            return false;
+            SwapchainStatus::Ok
         },
-        Err(VulkanError::OutOfDate) => {
-            return true;
-        },
-        Err(e) => panic!("Failed to present swapchain image: {}", e),
+        Err(e) => status_from_error(e, "present swapchain image"),
     }
 }
 
+// The acquired image is never presented when the frame's command buffer can't be submitted,
+// the swapchain is recreated so later acquires don't wait on it forever
+fn submit_failed(error: CommandBufferExecError) -> SwapchainStatus {
+    log::error!("Failed to submit frame, skipping it: {}", error);
+    SwapchainStatus::NeedsRecreate
+}
+
+// Submits the image's command buffer after previous_future and presents it
+// Returns the fence to wait on before the frame's resources are reused, and what the swapchain needs
 pub fn present_swapchain_image_with_fence(
-    device: Arc<Device>,
     swapchain: Arc<Swapchain>,
     queue: Arc<Queue>,
-    command_buffers: Vec<Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>>>,
+    command_buffer: Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>>,
     image_idx: u32,
     swapchain_image_future: SwapchainAcquireFuture,
     previous_future: Box<dyn GpuFuture>,
-    mut fences: Vec<Option<Arc<FenceSignalFuture<Box<dyn GpuFuture>>>>>
-) -> bool {
-    let mut recreate_swapchain = false;
-    let future = previous_future
+) -> (Option<FrameFence>, SwapchainStatus) {
+    let future = match previous_future
         .join(swapchain_image_future)
-        .then_execute(queue.clone(), command_buffers[image_idx as usize].clone())
-        .unwrap()
+        .then_execute(queue.clone(), command_buffer)
+    {
+        Ok(future) => future,
+        Err(e) => return (None, submit_failed(e)),
+    };
+    let future = future
         .then_swapchain_present(
             queue.clone(),
             SwapchainPresentInfo::swapchain_image_index(swapchain.clone(), image_idx)
@@ -146,16 +376,8 @@ pub fn present_swapchain_image_with_fence(
     let boxed_future = Box::new(future) as Box<dyn GpuFuture>;
     let execution_future = boxed_future.then_signal_fence_and_flush();
 
-    fences[image_idx as usize] = match execution_future.map_err(Validated::unwrap) {
-        Ok(v) => Some(Arc::new(v)),
-        Err(VulkanError::OutOfDate) => {
-            recreate_swapchain = true;
-            None
-        },
-        Err(e) => {
-            println!("Failed to present swapchain image: {}", e);
-            None
-        }
-    };
-    recreate_swapchain
+    match execution_future.map_err(Validated::unwrap) {
+        Ok(v) => (Some(Arc::new(v)), SwapchainStatus::Ok),
+        Err(e) => (None, status_from_error(e, "present swapchain image")),
+    }
 }
@@ -0,0 +1,266 @@
+use winit::event::{WindowEvent, DeviceEvent, ElementState, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, ModifiersState};
+use winit::window::{Window, WindowId, CursorGrabMode};
+use std::collections::HashSet;
+
+#[cfg(feature = "gamepad")]
+pub use gilrs::{Axis as GamepadAxis, Button as GamepadButton, GamepadId};
+
+// Roughly how many pixels one scroll wheel line is, to report line and pixel deltas in the same unit
+const PIXELS_PER_SCROLL_LINE: f32 = 20.0;
+
+// Keyboard, mouse and gamepad state, updated from winit events and reset at the end of every frame
+// "pressed" and "released" are only true on the frame the change happened, "held" for as long as the button is down
+#[derive(Default)]
+pub struct Input {
+    keys_held: HashSet<VirtualKeyCode>,
+    keys_pressed: HashSet<VirtualKeyCode>,
+    keys_released: HashSet<VirtualKeyCode>,
+    mouse_held: HashSet<MouseButton>,
+    mouse_pressed: HashSet<MouseButton>,
+    mouse_released: HashSet<MouseButton>,
+    modifiers: ModifiersState,
+    // Physical pixels from the top left of cursor_window
+    cursor_position: Option<[f32; 2]>,
+    cursor_window: Option<WindowId>,
+    // Raw device motion, keeps reporting while the cursor is grabbed
+    cursor_delta: [f32; 2],
+    scroll: [f32; 2],
+    text: String,
+    cursor_grabbed: bool,
+    #[cfg(feature = "gamepad")]
+    gamepads: Option<gilrs::Gilrs>,
+    #[cfg(feature = "gamepad")]
+    gamepad_pressed: HashSet<(GamepadId, GamepadButton)>,
+    #[cfg(feature = "gamepad")]
+    gamepad_released: HashSet<(GamepadId, GamepadButton)>,
+}
+
+impl Input {
+    pub fn new() -> Self {
+        #[allow(unused_mut)]
+        let mut input = Input::default();
+        #[cfg(feature = "gamepad")]
+        {
+            input.gamepads = match gilrs::Gilrs::new() {
+                Ok(gilrs) => Some(gilrs),
+                Err(e) => {
+                    log::warn!("Gamepad support unavailable: {}", e);
+                    None
+                }
+            };
+        }
+        input
+    }
+
+    pub fn handle_window_event(&mut self, window_id: WindowId, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput {
+                input: KeyboardInput { state, virtual_keycode: Some(key), .. },
+                ..
+            } => match state {
+                ElementState::Pressed => {
+                    // Key repeat sends more presses while the key is held, only the first one counts
+                    if self.keys_held.insert(*key) {
+                        self.keys_pressed.insert(*key);
+                    }
+                }
+                ElementState::Released => {
+                    self.keys_held.remove(key);
+                    self.keys_released.insert(*key);
+                }
+            },
+            WindowEvent::MouseInput { state, button, .. } => match state {
+                ElementState::Pressed => {
+                    if self.mouse_held.insert(*button) {
+                        self.mouse_pressed.insert(*button);
+                    }
+                }
+                ElementState::Released => {
+                    self.mouse_held.remove(button);
+                    self.mouse_released.insert(*button);
+                }
+            },
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.cursor_position = Some([position.x as f32, position.y as f32]);
+                self.cursor_window = Some(window_id);
+            }
+            WindowEvent::CursorLeft { .. } if self.cursor_window == Some(window_id) => {
+                self.cursor_position = None;
+                self.cursor_window = None;
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let [x, y] = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => [*x, *y],
+                    MouseScrollDelta::PixelDelta(position) => [
+                        position.x as f32 / PIXELS_PER_SCROLL_LINE,
+                        position.y as f32 / PIXELS_PER_SCROLL_LINE,
+                    ],
+                };
+                self.scroll[0] += x;
+                self.scroll[1] += y;
+            }
+            WindowEvent::ReceivedCharacter(c) if !c.is_control() => {
+                self.text.push(*c);
+            }
+            // Releases are not delivered to unfocused windows, drop everything so nothing stays stuck down
+            WindowEvent::Focused(false) => {
+                self.keys_released.extend(self.keys_held.drain());
+                self.mouse_released.extend(self.mouse_held.drain());
+                self.modifiers = ModifiersState::empty();
+            }
+            _ => (),
+        }
+    }
+
+    pub fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta } = event {
+            self.cursor_delta[0] += delta.0 as f32;
+            self.cursor_delta[1] += delta.1 as f32;
+        }
+    }
+
+    // Picks up gamepad events since the last frame, a no-op without the gamepad feature
+    pub fn begin_frame(&mut self) {
+        #[cfg(feature = "gamepad")]
+        if let Some(gamepads) = &mut self.gamepads {
+            while let Some(gilrs::Event { id, event, .. }) = gamepads.next_event() {
+                match event {
+                    gilrs::EventType::ButtonPressed(button, _) => {
+                        self.gamepad_pressed.insert((id, button));
+                    }
+                    gilrs::EventType::ButtonReleased(button, _) => {
+                        self.gamepad_released.insert((id, button));
+                    }
+                    gilrs::EventType::Connected => log::info!("Gamepad {} connected", gamepads.gamepad(id).name()),
+                    gilrs::EventType::Disconnected => log::info!("Gamepad {} disconnected", gamepads.gamepad(id).name()),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Clears the per frame state, called after the frame's update
+    pub fn end_frame(&mut self) {
+        self.keys_pressed.clear();
+        self.keys_released.clear();
+        self.mouse_pressed.clear();
+        self.mouse_released.clear();
+        self.cursor_delta = [0.0, 0.0];
+        self.scroll = [0.0, 0.0];
+        self.text.clear();
+        #[cfg(feature = "gamepad")]
+        {
+            self.gamepad_pressed.clear();
+            self.gamepad_released.clear();
+        }
+    }
+
+    pub fn is_key_held(&self, key: VirtualKeyCode) -> bool {
+        self.keys_held.contains(&key)
+    }
+
+    pub fn is_key_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.keys_pressed.contains(&key)
+    }
+
+    pub fn is_key_released(&self, key: VirtualKeyCode) -> bool {
+        self.keys_released.contains(&key)
+    }
+
+    pub fn is_mouse_held(&self, button: MouseButton) -> bool {
+        self.mouse_held.contains(&button)
+    }
+
+    pub fn is_mouse_pressed(&self, button: MouseButton) -> bool {
+        self.mouse_pressed.contains(&button)
+    }
+
+    pub fn is_mouse_released(&self, button: MouseButton) -> bool {
+        self.mouse_released.contains(&button)
+    }
+
+    pub fn modifiers(&self) -> ModifiersState {
+        self.modifiers
+    }
+
+    // None while the cursor is outside every window
+    pub fn cursor_position(&self) -> Option<[f32; 2]> {
+        self.cursor_position
+    }
+
+    pub fn cursor_window(&self) -> Option<WindowId> {
+        self.cursor_window
+    }
+
+    pub fn cursor_delta(&self) -> [f32; 2] {
+        self.cursor_delta
+    }
+
+    // In lines, positive y scrolls up
+    pub fn scroll(&self) -> [f32; 2] {
+        self.scroll
+    }
+
+    // Characters typed this frame
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_cursor_grabbed(&self) -> bool {
+        self.cursor_grabbed
+    }
+
+    // Hides the cursor and keeps it in the window, cursor_delta keeps reporting movement
+    // Locking isn't supported everywhere, confining is used instead
+    pub fn set_cursor_grab(&mut self, window: &Window, grab: bool) {
+        let result = if grab {
+            window.set_cursor_grab(CursorGrabMode::Locked)
+                .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+        } else {
+            window.set_cursor_grab(CursorGrabMode::None)
+        };
+        match result {
+            Ok(()) => {
+                window.set_cursor_visible(!grab);
+                self.cursor_grabbed = grab;
+            }
+            Err(e) => log::warn!("Failed to change cursor grab: {}", e),
+        }
+    }
+
+    #[cfg(feature = "gamepad")]
+    pub fn gamepads(&self) -> Vec<GamepadId> {
+        match &self.gamepads {
+            Some(gamepads) => gamepads.gamepads().map(|(id, _)| id).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    #[cfg(feature = "gamepad")]
+    pub fn is_gamepad_held(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.gamepads.as_ref()
+            .and_then(|gamepads| gamepads.connected_gamepad(id))
+            .map_or(false, |gamepad| gamepad.is_pressed(button))
+    }
+
+    #[cfg(feature = "gamepad")]
+    pub fn is_gamepad_pressed(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.gamepad_pressed.contains(&(id, button))
+    }
+
+    #[cfg(feature = "gamepad")]
+    pub fn is_gamepad_released(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.gamepad_released.contains(&(id, button))
+    }
+
+    // In [-1, 1], 0 for disconnected gamepads
+    #[cfg(feature = "gamepad")]
+    pub fn gamepad_axis(&self, id: GamepadId, axis: GamepadAxis) -> f32 {
+        self.gamepads.as_ref()
+            .and_then(|gamepads| gamepads.connected_gamepad(id))
+            .map_or(0.0, |gamepad| gamepad.value(axis))
+    }
+}
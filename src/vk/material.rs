@@ -0,0 +1,314 @@
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use vulkano::buffer::BufferUsage;
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::pipeline::PipelineLayout;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::vk::buffer;
+use crate::vk::image::Texture;
+use crate::vk::pipeline::PipelineState;
+use crate::vk::shader::ProgramHandle;
+
+// Where programs declare the material's resources, i.e.
+// `layout(set = 1, binding = 0) uniform Material { vec4 color; float roughness; };`
+// Textures go in the same set at the bindings they were added with
+pub const MATERIAL_SET: u32 = 1;
+pub const MATERIAL_PARAMS_BINDING: u32 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaterialValue {
+    Float(f32),
+    Int(i32),
+    UInt(u32),
+    Vec2(Vec2),
+    Vec3(Vec3),
+    Vec4(Vec4),
+    Mat4(Mat4),
+}
+
+impl MaterialValue {
+    // std140 alignment and size in bytes
+    fn layout(&self) -> (usize, usize) {
+        match self {
+            MaterialValue::Float(_) | MaterialValue::Int(_) | MaterialValue::UInt(_) => (4, 4),
+            MaterialValue::Vec2(_) => (8, 8),
+            MaterialValue::Vec3(_) => (16, 12),
+            MaterialValue::Vec4(_) => (16, 16),
+            MaterialValue::Mat4(_) => (16, 64),
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        let floats = match *self {
+            MaterialValue::Int(value) => return bytes.extend(value.to_ne_bytes()),
+            MaterialValue::UInt(value) => return bytes.extend(value.to_ne_bytes()),
+            MaterialValue::Float(value) => vec![value],
+            MaterialValue::Vec2(value) => value.to_array().to_vec(),
+            MaterialValue::Vec3(value) => value.to_array().to_vec(),
+            MaterialValue::Vec4(value) => value.to_array().to_vec(),
+            MaterialValue::Mat4(value) => value.to_cols_array().to_vec(),
+        };
+        bytes.extend(floats.iter().flat_map(|float| float.to_ne_bytes()));
+    }
+}
+
+// Packs the values in order with std140 rules, the program's uniform block has to declare them in the same order
+fn pack_std140(params: &[(String, MaterialValue)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (_, value) in params {
+        let (align, _) = value.layout();
+        bytes.resize(bytes.len().next_multiple_of(align), 0);
+        value.write(&mut bytes);
+    }
+    bytes.resize(bytes.len().next_multiple_of(16), 0);
+    bytes
+}
+
+// A program with the pipeline state, parameters and textures to draw it with
+// Materials with the same program and state share a pipeline, only their descriptor sets differ
+#[derive(Clone)]
+pub struct Material {
+    pub name: String,
+    program: ProgramHandle,
+    pub state: PipelineState,
+    params: Vec<(String, MaterialValue)>,
+    textures: Vec<(u32, Texture)>,
+    // Rebuilt on the next draw after a parameter or texture changes
+    descriptor_set: Option<Arc<PersistentDescriptorSet>>,
+}
+
+impl Material {
+    pub fn new(name: &str, program: ProgramHandle) -> Self {
+        Material {
+            name: name.to_string(),
+            program,
+            state: PipelineState::default(),
+            params: Vec::new(),
+            textures: Vec::new(),
+            descriptor_set: None,
+        }
+    }
+
+    pub fn program(&self) -> ProgramHandle {
+        self.program
+    }
+
+    pub fn with_state(mut self, state: PipelineState) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn with_param(mut self, name: &str, value: MaterialValue) -> Self {
+        self.set_param(name, value);
+        self
+    }
+
+    pub fn with_texture(mut self, binding: u32, texture: Texture) -> Self {
+        self.set_texture(binding, texture);
+        self
+    }
+
+    // New parameters are appended to the uniform block, existing ones keep their place
+    pub fn set_param(&mut self, name: &str, value: MaterialValue) {
+        match self.params.iter_mut().find(|(param, _)| param == name) {
+            Some((_, param_value)) => *param_value = value,
+            None => self.params.push((name.to_string(), value)),
+        }
+        self.descriptor_set = None;
+    }
+
+    pub fn param(&self, name: &str) -> Option<MaterialValue> {
+        self.params.iter().find(|(param, _)| param == name).map(|(_, value)| *value)
+    }
+
+    pub fn params(&self) -> &[(String, MaterialValue)] {
+        &self.params
+    }
+
+    pub fn set_texture(&mut self, binding: u32, texture: Texture) {
+        if binding == MATERIAL_PARAMS_BINDING {
+            panic!("Binding {} of material `{}` is reserved for its parameters", binding, self.name);
+        }
+        self.textures.retain(|(texture_binding, _)| *texture_binding != binding);
+        self.textures.push((binding, texture));
+        self.descriptor_set = None;
+    }
+
+    pub fn textures(&self) -> &[(u32, Texture)] {
+        &self.textures
+    }
+
+    // None when the program doesn't declare the material set
+    // The set is built against the first layout it's asked for, every pipeline of the program has a compatible one
+    // params_size is the size of the program's reflected parameter block, the packed parameters have to fill it exactly
+    pub(crate) fn descriptor_set(
+        &mut self,
+        layout: &PipelineLayout,
+        params_size: Option<u32>,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>
+    ) -> Option<Arc<PersistentDescriptorSet>> {
+        let set_layout = layout.set_layouts().get(MATERIAL_SET as usize)?;
+        if set_layout.bindings().is_empty() {
+            return None;
+        }
+        if let Some(descriptor_set) = &self.descriptor_set {
+            return Some(descriptor_set.clone());
+        }
+        let params = pack_std140(&self.params);
+        if let Some(params_size) = params_size {
+            if params.len() != (params_size as usize).next_multiple_of(16) {
+                panic!(
+                    "Material `{}` packs {} bytes of parameters but its program's parameter block is {} bytes, check the parameters' names and order",
+                    self.name,
+                    params.len(),
+                    params_size
+                );
+            }
+        }
+        let mut writes = Vec::new();
+        if !params.is_empty() {
+            // A new buffer every time, frames still in flight keep reading the old one
+            let params = buffer::create_buffer_from_iter(
+                memory_allocator,
+                buffer::UNIFORM_BUFFER_MEMORY_TYPE_FILTER,
+                BufferUsage::UNIFORM_BUFFER,
+                params.into_iter()
+            );
+            writes.push(WriteDescriptorSet::buffer(MATERIAL_PARAMS_BINDING, params));
+        }
+        for (binding, texture) in &self.textures {
+            writes.push(WriteDescriptorSet::image_view_sampler(*binding, texture.view.clone(), texture.sampler.clone()));
+        }
+        let descriptor_set = PersistentDescriptorSet::new(&descriptor_set_allocator, set_layout.clone(), writes, [])
+            .unwrap_or_else(|e| panic!("Material `{}` doesn't match its program's material set: {}", self.name, e));
+        self.descriptor_set = Some(descriptor_set.clone());
+        Some(descriptor_set)
+    }
+}
+
+// Handle to a material registered in a Materials library
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MaterialHandle(usize);
+
+#[derive(Default)]
+pub struct Materials {
+    materials: Vec<Material>,
+    names: HashMap<String, MaterialHandle>,
+}
+
+impl Materials {
+    pub fn new() -> Self {
+        Materials::default()
+    }
+
+    // Replaces any material already registered under the same name
+    pub fn add(&mut self, material: Material) -> MaterialHandle {
+        if let Some(&handle) = self.names.get(&material.name) {
+            self.materials[handle.0] = material;
+            return handle;
+        }
+        let handle = MaterialHandle(self.materials.len());
+        self.names.insert(material.name.clone(), handle);
+        self.materials.push(material);
+        handle
+    }
+
+    pub fn get(&self, handle: MaterialHandle) -> &Material {
+        &self.materials[handle.0]
+    }
+
+    pub fn get_mut(&mut self, handle: MaterialHandle) -> &mut Material {
+        &mut self.materials[handle.0]
+    }
+
+    pub fn find(&self, name: &str) -> Option<MaterialHandle> {
+        self.names.get(name).copied()
+    }
+
+    // Descriptor sets built against the program's old layout get rebuilt on their next draw
+    pub(crate) fn program_reloaded(&mut self, program: ProgramHandle) {
+        for material in self.materials.iter_mut().filter(|material| material.program == program) {
+            material.descriptor_set = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vk::pbr::PbrParams;
+
+    fn params(values: &[MaterialValue]) -> Vec<(String, MaterialValue)> {
+        values.iter().enumerate().map(|(i, value)| (format!("param{}", i), *value)).collect()
+    }
+
+    fn f32_at(bytes: &[u8], offset: usize) -> f32 {
+        f32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn float_after_vec3_fills_its_last_slot() {
+        let bytes = pack_std140(&params(&[MaterialValue::Vec3(Vec3::new(1.0, 2.0, 3.0)), MaterialValue::Float(4.0)]));
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(f32_at(&bytes, 8), 3.0);
+        assert_eq!(f32_at(&bytes, 12), 4.0);
+    }
+
+    #[test]
+    fn mat4_is_16_aligned() {
+        let bytes = pack_std140(&params(&[MaterialValue::Float(1.0), MaterialValue::Mat4(Mat4::from_diagonal(Vec4::splat(2.0)))]));
+        assert_eq!(bytes.len(), 16 + 64);
+        assert_eq!(f32_at(&bytes, 4), 0.0);
+        assert_eq!(f32_at(&bytes, 16), 2.0);
+        assert_eq!(f32_at(&bytes, 16 + 60), 2.0);
+    }
+
+    #[test]
+    fn tail_is_padded_to_16() {
+        assert_eq!(pack_std140(&[]).len(), 0);
+        assert_eq!(pack_std140(&params(&[MaterialValue::Float(1.0)])).len(), 16);
+        assert_eq!(pack_std140(&params(&[MaterialValue::Vec4(Vec4::ONE), MaterialValue::Vec2(Vec2::ONE)])).len(), 32);
+        assert_eq!(pack_std140(&params(&[MaterialValue::UInt(1), MaterialValue::Int(2), MaterialValue::Vec2(Vec2::ONE)])).len(), 16);
+    }
+
+    #[test]
+    fn pbr_params_pack_to_48_bytes() {
+        let pbr = PbrParams { metallic: 0.25, ..PbrParams::default() };
+        let material = pbr.params().into_iter()
+            .fold(Material::new("pbr", ProgramHandle(0)), |material, (param, value)| material.with_param(param, value));
+        let bytes = pack_std140(material.params());
+        assert_eq!(bytes.len(), 48);
+        // emissive_factor is a vec3 and metallic_factor packs into its last slot
+        assert_eq!(f32_at(&bytes, 28), 0.25);
+        assert_eq!(f32_at(&bytes, 44), -1.0);
+    }
+
+    #[test]
+    fn set_param_keeps_the_order() {
+        let mut material = Material::new("material", ProgramHandle(0))
+            .with_param("color", MaterialValue::Vec4(Vec4::ONE))
+            .with_param("roughness", MaterialValue::Float(0.5));
+        material.set_param("color", MaterialValue::Vec4(Vec4::ZERO));
+        material.set_param("metallic", MaterialValue::Float(1.0));
+        let names: Vec<&str> = material.params().iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["color", "roughness", "metallic"]);
+        assert_eq!(material.param("color"), Some(MaterialValue::Vec4(Vec4::ZERO)));
+    }
+
+    #[test]
+    fn adding_under_an_existing_name_replaces_in_place() {
+        let mut materials = Materials::new();
+        let first = materials.add(Material::new("first", ProgramHandle(0)));
+        let second = materials.add(Material::new("second", ProgramHandle(0)));
+        let replaced = materials.add(Material::new("first", ProgramHandle(1)).with_param("roughness", MaterialValue::Float(0.5)));
+        assert_eq!(replaced, first);
+        assert_ne!(replaced, second);
+        assert_eq!(materials.get(first).program(), ProgramHandle(1));
+        assert_eq!(materials.get(first).param("roughness"), Some(MaterialValue::Float(0.5)));
+        assert_eq!(materials.find("second"), Some(second));
+    }
+}
@@ -0,0 +1,203 @@
+use glam::{Vec2, Vec3};
+use vulkano::buffer::{IndexBuffer, Subbuffer};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::vk::buffer::{self, PrimaryCommandBufferBuilder};
+use crate::vk::debug;
+use crate::vk::Vert;
+
+// Axis aligned, in the mesh's local space
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Bounds {
+    pub fn from_points(points: impl IntoIterator<Item = Vec3>) -> Self {
+        let mut bounds = Bounds { min: Vec3::splat(f32::MAX), max: Vec3::splat(f32::MIN) };
+        let mut empty = true;
+        for point in points {
+            bounds.min = bounds.min.min(point);
+            bounds.max = bounds.max.max(point);
+            empty = false;
+        }
+        if empty {
+            Bounds { min: Vec3::ZERO, max: Vec3::ZERO }
+        } else {
+            bounds
+        }
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) / 2.0
+    }
+
+    pub fn extents(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        Bounds { min: self.min.min(other.min), max: self.max.max(other.max) }
+    }
+}
+
+// A range of the index buffer drawn with one material
+#[derive(Clone, Debug, PartialEq)]
+pub struct Submesh {
+    pub indices: Range<u32>,
+    // Added to every index of the range, lets submeshes use indices local to their own vertices
+    pub vertex_offset: i32,
+    // Index into whatever materials the mesh is drawn with
+    pub material_slot: usize,
+}
+
+impl Submesh {
+    pub fn new(indices: Range<u32>, material_slot: usize) -> Self {
+        Submesh { indices, vertex_offset: 0, material_slot }
+    }
+}
+
+pub struct Mesh {
+    pub name: String,
+    pub vertex_buffer: Subbuffer<[Vert]>,
+    pub index_buffer: IndexBuffer,
+    pub bounds: Bounds,
+    pub submeshes: Vec<Submesh>,
+}
+
+impl Mesh {
+    // Indices are stored as u16 when every one of them fits
+    // No submeshes means a single one covering every index with material slot 0
+    pub fn new(memory_allocator: Arc<StandardMemoryAllocator>, name: &str, vertices: &[Vert], indices: &[u32], submeshes: Vec<Submesh>) -> Arc<Mesh> {
+        assert!(!vertices.is_empty(), "Mesh `{}` has no vertices", name);
+        assert!(!indices.is_empty(), "Mesh `{}` has no indices", name);
+        let submeshes = if submeshes.is_empty() {
+            vec![Submesh::new(0..indices.len() as u32, 0)]
+        } else {
+            submeshes
+        };
+        for submesh in &submeshes {
+            if submesh.indices.start > submesh.indices.end || submesh.indices.end as usize > indices.len() {
+                panic!("Submesh {:?} of mesh `{}` is outside its {} indices", submesh.indices, name, indices.len());
+            }
+            let submesh_indices = &indices[submesh.indices.start as usize..submesh.indices.end as usize];
+            assert!(
+                submesh_indices.iter().all(|&index| (0..vertices.len() as i64).contains(&(index as i64 + submesh.vertex_offset as i64))),
+                "Submesh {:?} of mesh `{}` indexes past its {} vertices", submesh.indices, name, vertices.len()
+            );
+        }
+
+        let vertex_buffer = buffer::create_vertex_buffer(memory_allocator.clone(), vertices.iter().copied());
+        debug::set_object_name(&**vertex_buffer.buffer(), &format!("{} vertices", name));
+
+        let index_buffer = if indices.iter().all(|&index| index <= u16::MAX as u32) {
+            buffer::create_index_buffer(memory_allocator, indices.iter().map(|&index| index as u16))
+        } else {
+            buffer::create_index_buffer(memory_allocator, indices.iter().copied())
+        };
+        debug::set_object_name(&**index_buffer.as_bytes().buffer(), &format!("{} indices", name));
+
+        Arc::new(Mesh {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            bounds: Bounds::from_points(vertices.iter().map(|vertex| Vec3::from(vertex.position))),
+            submeshes,
+        })
+    }
+
+    pub fn vertex_count(&self) -> u32 {
+        self.vertex_buffer.len() as u32
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_buffer.len() as u32
+    }
+
+    pub fn material_slot_count(&self) -> usize {
+        self.submeshes.iter().map(|submesh| submesh.material_slot + 1).max().unwrap_or(0)
+    }
+}
+
+// Smooth normals from the area weighted face normals, indices are local to vertices
+pub fn compute_normals(vertices: &mut [Vert], indices: &[u32]) {
+    let mut normals = vec![Vec3::ZERO; vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+        let [pa, pb, pc] = [a, b, c].map(|i| Vec3::from(vertices[i].position));
+        let face_normal = (pb - pa).cross(pc - pa);
+        normals[a] += face_normal;
+        normals[b] += face_normal;
+        normals[c] += face_normal;
+    }
+    for (vertex, normal) in vertices.iter_mut().zip(normals) {
+        vertex.normal = normal.normalize_or_zero().to_array();
+    }
+}
+
+// Per vertex tangents from the uv gradients of the triangles using it, indices are local to vertices
+// Vertices whose triangles have degenerate uvs get a tangent perpendicular to their normal
+pub fn compute_tangents(vertices: &mut [Vert], indices: &[u32]) {
+    let mut tangents = vec![Vec3::ZERO; vertices.len()];
+    let mut bitangents = vec![Vec3::ZERO; vertices.len()];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0] as usize, triangle[1] as usize, triangle[2] as usize];
+        let [pa, pb, pc] = [a, b, c].map(|i| Vec3::from(vertices[i].position));
+        let [ua, ub, uc] = [a, b, c].map(|i| Vec2::from(vertices[i].uv));
+        let (edge1, edge2) = (pb - pa, pc - pa);
+        let (duv1, duv2) = (ub - ua, uc - ua);
+        let determinant = duv1.x * duv2.y - duv2.x * duv1.y;
+        if determinant.abs() < f32::EPSILON {
+            continue;
+        }
+        let tangent = (edge1 * duv2.y - edge2 * duv1.y) / determinant;
+        let bitangent = (edge2 * duv1.x - edge1 * duv2.x) / determinant;
+        for i in [a, b, c] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+    for (i, vertex) in vertices.iter_mut().enumerate() {
+        let normal = Vec3::from(vertex.normal);
+        // Gram-Schmidt so the tangent is perpendicular to the normal
+        let tangent = (tangents[i] - normal * normal.dot(tangents[i])).try_normalize()
+            .unwrap_or_else(|| normal.any_orthonormal_vector());
+        let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        vertex.tangent = tangent.extend(handedness).to_array();
+    }
+}
+
+// Binds the mesh's buffers and draws every submesh, the pipeline and descriptor sets have to be bound already
+pub fn record_mesh_draw(builder: PrimaryCommandBufferBuilder, mesh: &Mesh, instance_count: u32) -> PrimaryCommandBufferBuilder {
+    let mut builder = bind_mesh_buffers(builder, mesh);
+    for submesh in &mesh.submeshes {
+        builder = record_submesh_draw(builder, submesh, instance_count);
+    }
+    builder
+}
+
+pub fn bind_mesh_buffers(mut builder: PrimaryCommandBufferBuilder, mesh: &Mesh) -> PrimaryCommandBufferBuilder {
+    builder
+        .bind_vertex_buffers(0, mesh.vertex_buffer.clone())
+        .unwrap()
+        .bind_index_buffer(mesh.index_buffer.clone())
+        .unwrap();
+    builder
+}
+
+// The submesh's mesh has to be bound with bind_mesh_buffers
+pub fn record_submesh_draw(mut builder: PrimaryCommandBufferBuilder, submesh: &Submesh, instance_count: u32) -> PrimaryCommandBufferBuilder {
+    builder
+        .draw_indexed(
+            submesh.indices.end - submesh.indices.start,
+            instance_count,
+            submesh.indices.start,
+            submesh.vertex_offset,
+            0
+        )
+        .unwrap();
+    builder
+}
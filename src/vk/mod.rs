@@ -1,21 +1,27 @@
 use vulkano::VulkanLibrary;
 use vulkano::instance::{Instance, InstanceCreateInfo, InstanceCreateFlags, InstanceExtensions};
-use vulkano::device::{Device, Queue, DeviceExtensions};
+use vulkano::instance::debug::DebugUtilsMessenger;
+use vulkano::device::{Device, Queue, DeviceExtensions, Features};
 use vulkano::memory::allocator::StandardMemoryAllocator;
 use vulkano::command_buffer::allocator::{StandardCommandBufferAllocator, StandardCommandBufferAllocatorCreateInfo};
 use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
-use vulkano::swapchain::{Surface, Swapchain};
+use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
+use vulkano::buffer::allocator::{SubbufferAllocator, SubbufferAllocatorCreateInfo};
+use vulkano::memory::allocator::MemoryTypeFilter;
+use vulkano::swapchain::{Surface, Swapchain, SwapchainAcquireFuture};
+use vulkano::format::Format;
 use vulkano::buffer::BufferUsage;
 use vulkano::device::physical::PhysicalDevice;
 use vulkano::pipeline::graphics::viewport::Viewport;
-use winit::event_loop::{EventLoop, ControlFlow};
+use winit::event_loop::{EventLoop, EventLoopWindowTarget, ControlFlow};
 use winit::event::{Event, WindowEvent};
-use winit::window::{Window, WindowBuilder};
+use winit::window::{Window, WindowId};
 use vulkano::image::Image;
 use vulkano::render_pass::{RenderPass, Framebuffer};
 use vulkano::buffer::BufferContents;
 use vulkano::pipeline::compute::ComputePipeline;
 use vulkano::pipeline::graphics::vertex_input::Vertex;
+use vulkano::pipeline::{Pipeline, PipelineBindPoint};
 use vulkano::pipeline::graphics::GraphicsPipeline;
 use vulkano::command_buffer::{PrimaryAutoCommandBuffer};
 use vulkano::buffer::{Subbuffer, IndexBuffer};
@@ -28,13 +34,23 @@ use vulkano::{VulkanError, Validated};
 
 
 use std::sync::Arc;
+use std::collections::HashMap;
+use std::path::Path;
 
 
-#[derive(BufferContents, Vertex, Clone, Copy)]
+#[derive(BufferContents, Vertex, Clone, Copy, Debug, Default, PartialEq)]
 #[repr(C)]
 pub struct Vert {
     #[format(R32G32B32_SFLOAT)]
     pub position: [f32; 3],
+    #[format(R32G32B32_SFLOAT)]
+    pub normal: [f32; 3],
+    // Origin at the top left of the texture
+    #[format(R32G32_SFLOAT)]
+    pub uv: [f32; 2],
+    // xyz points along increasing u, w is the handedness of the bitangent
+    #[format(R32G32B32A32_SFLOAT)]
+    pub tangent: [f32; 4],
 }
 
 
@@ -43,231 +59,713 @@ mod buffer;
 mod shader;
 mod pipeline;
 mod image;
+mod debug;
+mod frame;
+mod window;
+mod input;
+mod time;
+mod camera;
+mod mesh;
+mod obj;
+mod gltf_import;
+mod scene;
+mod material;
+mod pbr;
+mod shadow;
+pub mod primitives;
+
+pub use debug::{DebugConfig, ValidationErrors};
+pub use frame::{FramesInFlight, PerFrame};
+pub use window::{WindowConfig, FullscreenMode, DpiMode, WindowIcon, RenderWindow, WindowContent};
+pub use image::{SwapchainConfig, PresentPreference, ColorPreference, SwapchainStatus};
+pub use device::{DeviceSelection, DeviceRequirements, PhysicalDeviceReport};
+pub use input::Input;
+pub use time::{Clock, TimeConfig};
+pub use camera::{Camera, CameraUniform, Projection, FlyCamera, OrbitCamera, CAMERA_SET, CAMERA_BINDING};
+pub use mesh::{Mesh, Submesh, Bounds};
+pub use obj::{ObjModel, ObjMaterial};
+pub use image::Texture;
+pub use primitives::MeshData;
+pub use gltf_import::{
+    GltfScene, GltfImportOptions, GltfMesh, GltfMaterial, GltfNode, GltfCamera, GltfLight, GltfSkin,
+    GltfAnimation, GltfChannel, ChannelValues, Interpolation, AlphaMode,
+};
+pub use scene::{Scene, Node, NodeId, Transform, Light, LightKind, DrawItem, ModelPushConstants};
+pub use material::{Material, MaterialHandle, MaterialValue, MATERIAL_SET, MATERIAL_PARAMS_BINDING};
+pub use pipeline::{PipelineState, BlendMode, FaceCulling};
+pub use shader::ProgramHandle;
+pub use pbr::{PbrParams, LIGHTS_BINDING};
+pub use shadow::{ShadowConfig, SHADOW_LAYERS_BINDING, SHADOW_MAP_BINDING};
+pub use glam;
+#[cfg(feature = "gamepad")]
+pub use input::{GamepadAxis, GamepadButton, GamepadId};
+pub use winit::event::{VirtualKeyCode, MouseButton, ModifiersState};
+
+// Options used when the app is created, VkApp::new uses the defaults
+#[derive(Clone)]
+pub struct VkAppConfig {
+    pub device_selection: DeviceSelection,
+    // khr_swapchain is always added to the required extensions
+    pub device_requirements: DeviceRequirements,
+    pub debug: DebugConfig,
+    // How many frames the CPU may record ahead of the GPU, independent of the swapchain image count
+    pub frames_in_flight: usize,
+    pub swapchain: SwapchainConfig,
+    pub window: WindowConfig,
+    pub time: TimeConfig,
+    pub shadows: ShadowConfig,
+}
+
+impl Default for VkAppConfig {
+    fn default() -> Self {
+        VkAppConfig {
+            device_selection: DeviceSelection::default(),
+            device_requirements: DeviceRequirements::default(),
+            debug: DebugConfig::default(),
+            frames_in_flight: frame::DEFAULT_FRAMES_IN_FLIGHT,
+            swapchain: SwapchainConfig::default(),
+            window: WindowConfig::default(),
+            time: TimeConfig::default(),
+            shadows: ShadowConfig::default(),
+        }
+    }
+}
 
 pub struct VkApp {
     instance: Arc<Instance>,
+    // Only kept alive, messages stop when it's dropped
+    _debug_messenger: Option<DebugUtilsMessenger>,
+    validation_errors: ValidationErrors,
     device: Arc<Device>,
-    queue: Arc<Queue>,
-    queue_family_index: u32,
+    queues: device::Queues,
     command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
     memory_allocator: Arc<StandardMemoryAllocator>,
     descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>,
     shaders: shader::Shaders,
-    window: Arc<Window>,
-    surface: Arc<Surface>,
     physical_device: Arc<PhysicalDevice>,
-    swapchain: Arc<Swapchain>,
-    swapchain_images: Vec<Arc<Image>>,
-    render_pass: Arc<RenderPass>,
-    framebuffers: Vec<Arc<Framebuffer>>,
-    event_loop: EventLoop<()>,
-    viewport: Viewport,
-    graphics_pipeline: Option<Arc<GraphicsPipeline>>,
+    // The main window is always first, closing it exits the app
+    windows: Vec<RenderWindow>,
+    frames_in_flight: usize,
+    event_loop: Option<EventLoop<()>>,
+    materials: material::Materials,
+    // Drawn for submeshes whose node has no material for their slot
+    default_material: Option<MaterialHandle>,
+    // Created the first time a PBR material is
+    pbr: Option<pbr::PbrResources>,
+    shadow_config: ShadowConfig,
+    // Created the first frame a program samples the shadow map
+    shadows: Option<shadow::ShadowRenderer>,
+    // Per frame data like the light list, memory is reused once the frames using it are done
+    frame_allocator: SubbufferAllocator,
+    // Shared by every material with the same program and state, and every window whose render pass has the same format
+    graphics_pipelines: HashMap<(ProgramHandle, PipelineState, Format), Arc<GraphicsPipeline>>,
     compute_pipeline: Option<Arc<ComputePipeline>>,
-    command_buffers: Option<Vec<Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>>>>,
-    vertex_buffer: Option<Arc<Subbuffer<[Vert]>>>,
-    index_buffer: Option<Arc<IndexBuffer>>,
-    previous_fence_idx: u32,
+    scene: Scene,
+    input: Input,
+    clock: Clock,
+    // Opened on the next event loop turn
+    window_requests: Vec<window::WindowRequest>,
+}
+
+impl Default for VkApp {
+    fn default() -> Self {
+        VkApp::new()
+    }
 }
 
 impl VkApp {
     pub fn new() -> VkApp {
+        VkApp::with_config(VkAppConfig::default())
+    }
+
+    pub fn with_config(mut config: VkAppConfig) -> VkApp {
         let event_loop = EventLoop::new();
         let required_extensions = Surface::required_extensions(&event_loop);
-        let instance = create_instance(required_extensions);
-        let window = Arc::new(WindowBuilder::new().build(&event_loop).unwrap());
-        let surface = Surface::from_window(instance.clone(), window.clone()).unwrap();
-
-        let mut viewport = Viewport {
-            offset: [0.0, 0.0],
-            extent: window.inner_size().into(),
-            depth_range: 0.0..=1.0,
+        let instance = create_instance(required_extensions, &config.debug);
+        let validation_errors = ValidationErrors::default();
+        let debug_messenger = if config.debug.enabled && instance.enabled_extensions().ext_debug_utils {
+            Some(debug::create_debug_messenger(instance.clone(), &config.debug, validation_errors.clone()))
+        } else {
+            None
         };
-    
+        let window = window::create_window(&event_loop, &config.window);
+        config.swapchain.transparent |= config.window.transparent;
+        // Only used to pick a device that can present, the main RenderWindow creates its own
+        let surface = Surface::from_window(instance.clone(), window.clone()).unwrap();
 
-        let device_extensions = DeviceExtensions {
+        let mut device_requirements = config.device_requirements.clone();
+        device_requirements.required_extensions = device_requirements.required_extensions.union(&DeviceExtensions {
             khr_swapchain: true,
             ..DeviceExtensions::empty()
-        };        
-        let (device, queue, queue_family_index, physical_device) = device::create_device(instance.clone(), device_extensions, surface.clone());
+        });
+        let (device, queues, physical_device) = device::create_device(instance.clone(), &device_requirements, surface.clone(), &config.device_selection);
+        drop(surface);
         let command_buffer_allocator = Arc::new(StandardCommandBufferAllocator::new(
             device.clone(),
             StandardCommandBufferAllocatorCreateInfo::default()
         ));
         let memory_allocator = Arc::new(StandardMemoryAllocator::new_default(device.clone()));
         let descriptor_set_allocator = Arc::new(StandardDescriptorSetAllocator::new(device.clone(), Default::default()));
-        let mut shaders = shader::Shaders::new(device.clone());
+        let shaders = shader::Shaders::new(device.clone());
+        let frame_allocator = SubbufferAllocator::new(memory_allocator.clone(), SubbufferAllocatorCreateInfo {
+            buffer_usage: BufferUsage::STORAGE_BUFFER,
+            memory_type_filter: MemoryTypeFilter::PREFER_DEVICE | MemoryTypeFilter::HOST_SEQUENTIAL_WRITE,
+            ..Default::default()
+        });
 
-        let (swapchain, swapchain_images) = image::create_swapchain(device.clone(), window.clone(), surface.clone(), physical_device.clone());
-        let render_pass = pipeline::create_render_pass(device.clone(), swapchain.image_format());
-        let framebuffers = pipeline::create_framebuffers(render_pass.clone(), swapchain_images.clone());
+        let main_window = RenderWindow::new(
+            instance.clone(),
+            device.clone(),
+            &queues.graphics,
+            memory_allocator.clone(),
+            window,
+            config.swapchain.clone(),
+            config.frames_in_flight
+        );
 
 
         VkApp {
             instance,
+            _debug_messenger: debug_messenger,
+            validation_errors,
             device,
-            queue,
-            queue_family_index,
+            queues,
             command_buffer_allocator,
             memory_allocator,
             descriptor_set_allocator,
             shaders,
-            window,
-            surface,
             physical_device,
-            viewport,
-            swapchain,
-            swapchain_images,
-            render_pass,
-            framebuffers,
-            event_loop,
-            graphics_pipeline: None,
+            windows: vec![main_window],
+            frames_in_flight: config.frames_in_flight,
+            event_loop: Some(event_loop),
+            materials: material::Materials::new(),
+            default_material: None,
+            pbr: None,
+            shadow_config: config.shadows.clone(),
+            shadows: None,
+            frame_allocator,
+            graphics_pipelines: HashMap::new(),
             compute_pipeline: None,
-            command_buffers: None,
-            vertex_buffer: None,
-            index_buffer: None,
-            previous_fence_idx: 0,
+            scene: Scene::new(),
+            input: Input::new(),
+            clock: Clock::new(config.time.clone()),
+            window_requests: Vec::new(),
         }
     }
 
-    pub fn run(mut self) {
-        println!("Running App");
-        let mut window_resized = false;
-        let mut recreate_swapchain = false;
-        let mut fences: Vec<Option<Arc<FenceSignalFuture<Box<dyn GpuFuture>>>>> = vec![None; self.swapchain_images.len()];
+    // Opens another window presenting from the same device, it draws the whole scene with its own camera
+    // Has to be called before run, use request_window once the app runs
+    pub fn open_window(&mut self, window_config: WindowConfig, swapchain_config: SwapchainConfig) -> WindowId {
+        let event_loop = self.event_loop.as_ref().expect("Windows can only be opened before the app runs, use request_window");
+        let window = window::create_window(event_loop, &window_config);
+        self.add_window(window, &window_config, swapchain_config)
+    }
 
+    // Opens a window at the start of the next frame, for apps that are already running
+    // Its id shows up in window_ids from then on
+    pub fn request_window(&mut self, window_config: WindowConfig, swapchain_config: SwapchainConfig, content: WindowContent, camera: Camera) {
+        self.window_requests.push(window::WindowRequest { window: window_config, swapchain: swapchain_config, content, camera });
+    }
 
-        self.event_loop.run(move |event, _, control_flow| match event {
-            Event::WindowEvent {
-                event: WindowEvent::CloseRequested,
-                ..
-            } => {
-                *control_flow = ControlFlow::Exit;
-            }
-            Event::WindowEvent {
-                event: WindowEvent::Resized(new_size),
-                ..
-            } => {
-                if new_size.width > 0 && new_size.height > 0 {
-                    window_resized = true;
-                    println!("Window resized to {}x{}", new_size.width, new_size.height);
-                }
-            }
-            Event::MainEventsCleared => {
-                let (image_result, needs_recreate) = image::obtain_next_swapchain_image(self.swapchain.clone());
-                
-                if window_resized || recreate_swapchain || needs_recreate {
-                    recreate_swapchain = false;
-                    let new_dimensions = self.window.inner_size();
-                    
-                    if new_dimensions.width > 0 && new_dimensions.height > 0 {
-                        let (swapchain, swapchain_images) = image::recreate_swapchain(
-                            self.swapchain.clone(),
-                            new_dimensions.into()
-                        );
-                        self.swapchain = swapchain;
-                        self.swapchain_images = swapchain_images;
-                        self.framebuffers = pipeline::create_framebuffers(
-                            self.render_pass.clone(),
-                            self.swapchain_images.clone()
-                        );
-                        
-                        if window_resized {
-                            window_resized = false;
-                            self.viewport.extent = new_dimensions.into();
-                            
-                            let pipeline = pipeline::create_graphics_pipeline(
-                                self.device.clone(),
-                                &self.shaders,
-                                self.viewport.clone(),
-                                self.render_pass.clone()
-                            );
-                            self.graphics_pipeline = Some(pipeline);
-                            
-                            if let Some(vertex_buffer) = &self.vertex_buffer {
-                                let mut new_command_buffers = Vec::new();
-                                for framebuffer in &self.framebuffers {
-                                    let command_buffer_builder = buffer::create_command_buffer_builder(
-                                        self.command_buffer_allocator.clone(),
-                                        self.queue.clone()
-                                    );
-                                    let command_buffer_builder = pipeline::record_render_pass(
-                                        command_buffer_builder,
-                                        self.render_pass.clone(),
-                                        framebuffer.clone(),
-                                        self.graphics_pipeline.as_ref().unwrap().clone(),
-                                        0,
-                                        vertex_buffer.clone(),
-                                        self.index_buffer.as_ref().unwrap().clone(),
-                                        self.index_buffer.as_ref().unwrap().len() as u32,
-                                        self.index_buffer.as_ref().unwrap().len() as u32 / 3,
-                                        0,
-                                        0
-                                    );
-                                    let command_buffer = buffer::build_command_buffer(command_buffer_builder);
-                                    new_command_buffers.push(command_buffer);
-                                }
-                                self.command_buffers = Some(new_command_buffers);
+    fn open_requested_windows(&mut self, target: &EventLoopWindowTarget<()>) {
+        for request in std::mem::take(&mut self.window_requests) {
+            let window = window::create_window(target, &request.window);
+            let id = self.add_window(window, &request.window, request.swapchain);
+            let i = self.window_index(id).unwrap();
+            self.windows[i].camera = request.camera;
+            self.windows[i].content = request.content;
+        }
+    }
+
+    fn add_window(&mut self, window: Arc<Window>, window_config: &WindowConfig, swapchain_config: SwapchainConfig) -> WindowId {
+        let mut swapchain_config = swapchain_config;
+        swapchain_config.transparent |= window_config.transparent;
+        let render_window = RenderWindow::new(
+            self.instance.clone(),
+            self.device.clone(),
+            &self.queues.graphics,
+            self.memory_allocator.clone(),
+            window,
+            swapchain_config,
+            self.frames_in_flight
+        );
+        let id = render_window.id();
+        self.windows.push(render_window);
+        id
+    }
+
+    pub fn main_window_id(&self) -> WindowId {
+        self.windows[0].id()
+    }
+
+    pub fn window(&self, id: WindowId) -> Option<&RenderWindow> {
+        self.windows.iter().find(|window| window.id() == id)
+    }
+
+    pub fn window_mut(&mut self, id: WindowId) -> Option<&mut RenderWindow> {
+        self.windows.iter_mut().find(|window| window.id() == id)
+    }
+
+    // The main window first
+    pub fn window_ids(&self) -> Vec<WindowId> {
+        self.windows.iter().map(|window| window.id()).collect()
+    }
+
+    fn window_index(&self, id: WindowId) -> Option<usize> {
+        self.windows.iter().position(|window| window.id() == id)
+    }
+
+    pub fn graphics_queue(&self) -> Arc<Queue> {
+        self.queues.graphics.clone()
+    }
+
+    // Same as the graphics queue if the device has no dedicated compute family
+    pub fn compute_queue(&self) -> Arc<Queue> {
+        self.queues.compute.clone()
+    }
+
+    // Same as the graphics queue if the device has no dedicated transfer family
+    pub fn transfer_queue(&self) -> Arc<Queue> {
+        self.queues.transfer.clone()
+    }
+
+    // Distinct queue families in use, pass to buffer::create_shared_buffer_from_iter for cross-queue buffers
+    pub fn queue_family_indices(&self) -> Vec<u32> {
+        self.queues.family_indices()
+    }
+
+    // Required features plus the optional ones the device supported
+    pub fn enabled_features(&self) -> &Features {
+        self.device.enabled_features()
+    }
+
+    pub fn enabled_extensions(&self) -> &DeviceExtensions {
+        self.device.enabled_extensions()
+    }
+
+    // The main window's frames, for creating PerFrame resources and finding the slot being recorded
+    pub fn frames(&self) -> &FramesInFlight {
+        &self.windows[0].frames
+    }
+
+    // Empty unless debug mode was enabled with record_validation_errors
+    pub fn validation_errors(&self) -> ValidationErrors {
+        self.validation_errors.clone()
+    }
+
+    // Lists every physical device the instance can see, not only the one in use
+    pub fn physical_device_reports(&self) -> Vec<PhysicalDeviceReport> {
+        device::list_physical_devices(self.instance.clone())
+    }
+
+    pub fn input(&self) -> &Input {
+        &self.input
+    }
+
+    // Grabs the cursor in the main window, i.e. for mouse look
+    pub fn set_cursor_grab(&mut self, grab: bool) {
+        let window = self.windows[0].window.clone();
+        self.input.set_cursor_grab(&window, grab);
+    }
+
+    // The main window's camera, the scene's active camera node drives it
+    pub fn camera(&self) -> &Camera {
+        &self.windows[0].camera
+    }
+
+    // The aspect ratio is overwritten from the window's viewport when drawing
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.windows[0].camera
+    }
+
+    // Draws only the node's subtree or the whole scene in the window
+    pub fn set_window_content(&mut self, id: WindowId, content: WindowContent) {
+        self.window_mut(id).expect("Unknown window").content = content;
+    }
+
+    pub fn scene(&self) -> &Scene {
+        &self.scene
+    }
+
+    // Changes show up in the next frame drawn
+    pub fn scene_mut(&mut self) -> &mut Scene {
+        &mut self.scene
+    }
+
+    // Stages are inferred from the file extensions, loading a program under an existing name replaces it
+    pub fn load_program(&mut self, name: &str, vertex_path: impl AsRef<Path>, fragment_path: impl AsRef<Path>) -> ProgramHandle {
+        let program = self.shaders.load_program_from_files(name, vertex_path, fragment_path);
+        self.graphics_pipelines.retain(|(pipeline_program, _, _), _| *pipeline_program != program);
+        self.materials.program_reloaded(program);
+        program
+    }
+
+    // Adding a material under an existing name replaces it
+    pub fn add_material(&mut self, material: Material) -> MaterialHandle {
+        self.materials.add(material)
+    }
+
+    pub fn material(&self, handle: MaterialHandle) -> &Material {
+        self.materials.get(handle)
+    }
+
+    // Parameter and texture changes show up in the next frame drawn
+    pub fn material_mut(&mut self, handle: MaterialHandle) -> &mut Material {
+        self.materials.get_mut(handle)
+    }
+
+    pub fn find_material(&self, name: &str) -> Option<MaterialHandle> {
+        self.materials.find(name)
+    }
+
+    pub fn set_default_material(&mut self, material: Option<MaterialHandle>) {
+        self.default_material = material;
+    }
+
+    // The built-in metallic-roughness program, lit by the scene's light components and ambient color
+    pub fn pbr_program(&mut self) -> ProgramHandle {
+        self.pbr_resources().program
+    }
+
+    fn pbr_resources(&mut self) -> &pbr::PbrResources {
+        if self.pbr.is_none() {
+            self.pbr = Some(pbr::PbrResources::new(
+                &mut self.shaders,
+                self.memory_allocator.clone(),
+                self.command_buffer_allocator.clone(),
+                self.queues.graphics.clone()
+            ));
+        }
+        self.pbr.as_ref().unwrap()
+    }
+
+    pub fn create_pbr_material(&mut self, name: &str, params: &PbrParams) -> MaterialHandle {
+        let material = params.to_material(name, self.pbr_resources());
+        self.add_material(material)
+    }
+
+    // One PBR material per material of the file, named `prefix/index name`, ready for Scene::add_gltf
+    pub fn add_gltf_materials(&mut self, prefix: &str, gltf: &GltfScene) -> Vec<MaterialHandle> {
+        gltf.materials.iter().enumerate().map(|(i, material)| {
+            let params = PbrParams::from_gltf(material, &gltf.textures);
+            self.create_pbr_material(&format!("{}/{} {}", prefix, i, material.name), &params)
+        }).collect()
+    }
+
+    pub fn shadow_config(&self) -> &ShadowConfig {
+        &self.shadow_config
+    }
+
+    // The shadow pipeline and every window's shadow maps are recreated on the next frame
+    pub fn set_shadow_config(&mut self, config: ShadowConfig) {
+        self.shadow_config = config;
+        self.shadows = None;
+        for window in &mut self.windows {
+            window.shadow_maps = None;
+        }
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    // Frame rate cap and deterministic mode can be changed while running
+    pub fn set_time_config(&mut self, config: TimeConfig) {
+        self.clock.set_config(config);
+    }
+
+    pub fn run(self) {
+        self.run_with(|_| ());
+    }
+
+    // Runs the event loop, calling update once per frame after the frame's input has been gathered and before drawing
+    pub fn run_with(self, update: impl FnMut(&mut VkApp) + 'static) {
+        self.run_with_fixed_update(|_, _| (), update);
+    }
+
+    // Like run_with, fixed_update runs before update as many times as fit in the frame, with the fixed timestep in seconds
+    // Use clock().alpha() in update to interpolate between fixed steps
+    pub fn run_with_fixed_update(
+        mut self,
+        mut fixed_update: impl FnMut(&mut VkApp, f32) + 'static,
+        mut update: impl FnMut(&mut VkApp) + 'static
+    ) {
+        println!("Running App");
+        let event_loop = self.event_loop.take().expect("App is already running");
+
+        event_loop.run(move |event, target, control_flow| match event {
+            Event::WindowEvent { event, window_id } => {
+                self.input.handle_window_event(window_id, &event);
+                match event {
+                    WindowEvent::CloseRequested => {
+                        if window_id == self.main_window_id() {
+                            for window in self.windows.iter_mut() {
+                                window.frames.wait_idle();
                             }
+                            *control_flow = ControlFlow::Exit;
+                        } else if let Some(i) = self.window_index(window_id) {
+                            let mut window = self.windows.remove(i);
+                            window.frames.wait_idle();
                         }
-                        
-                        // Re-acquire image after recreating swapchain
-                        let (new_image_result, _) = image::obtain_next_swapchain_image(self.swapchain.clone());
-                        if let Some((image_idx, swapchain_future)) = new_image_result {
-                            if let Some(fence) = &fences[image_idx as usize] {
-                                fence.wait(None).unwrap();
-                            }
-                            let previous_future = match &fences[self.previous_fence_idx as usize] {
-                                Some(fence) => Box::new(fence.clone()) as Box<dyn GpuFuture>,
-                                None => {
-                                    let mut now = sync::now(self.device.clone());
-                                    now.cleanup_finished();
-                                    Box::new(now) as Box<dyn GpuFuture>
-                                }
-                            };
-                            recreate_swapchain = image::present_swapchain_image_with_fence(
-                                self.device.clone(),
-                                self.swapchain.clone(),
-                                self.queue.clone(),
-                                self.command_buffers.as_ref().unwrap().clone(),
-                                image_idx,
-                                swapchain_future,
-                                previous_future,
-                                fences.clone(),
-                            );
-                            self.previous_fence_idx = image_idx;
+                    }
+                    WindowEvent::Resized(new_size) => {
+                        println!("Window resized to {}x{}", new_size.width, new_size.height);
+                        if let Some(i) = self.window_index(window_id) {
+                            let window = &mut self.windows[i];
+                            window.swapchain_status = window.swapchain_status.merge(SwapchainStatus::NeedsRecreate);
                         }
                     }
-                } else if let Some((image_idx, swapchain_future)) = image_result {
-                    // Normal presentation path (no resize)
-                    if let Some(fence) = &fences[image_idx as usize] {
-                        fence.wait(None).unwrap();
+                    _ => (),
+                }
+            }
+            Event::DeviceEvent { event, .. } => {
+                self.input.handle_device_event(&event);
+            }
+            Event::MainEventsCleared => {
+                self.open_requested_windows(target);
+                self.clock.wait_for_next_frame();
+                let fixed_steps = self.clock.tick();
+                self.input.begin_frame();
+                let fixed_timestep = self.clock.fixed_timestep().as_secs_f32();
+                for _ in 0..fixed_steps {
+                    fixed_update(&mut self, fixed_timestep);
+                }
+                update(&mut self);
+                self.input.end_frame();
+                self.scene.update_world_transforms();
+                self.scene.apply_active_camera(&mut self.windows[0].camera);
+
+                // Nothing can be presented to minimized windows, sleep until the next event instead of spinning
+                // when all of them are
+                let mut any_visible = false;
+                for i in 0..self.windows.len() {
+                    if self.windows[i].is_minimized() {
+                        continue;
                     }
-                    let previous_future = match &fences[self.previous_fence_idx as usize] {
-                        Some(fence) => Box::new(fence.clone()) as Box<dyn GpuFuture>,
-                        None => {
-                            let mut now = sync::now(self.device.clone());
-                            now.cleanup_finished();
-                            Box::new(now) as Box<dyn GpuFuture>
-                        }
-                    };
-                    recreate_swapchain = image::present_swapchain_image_with_fence(
-                        self.device.clone(),
-                        self.swapchain.clone(),
-                        self.queue.clone(),
-                        self.command_buffers.as_ref().unwrap().clone(),
-                        image_idx,
-                        swapchain_future,
-                        previous_future,
-                        fences.clone(),
-                    );
-                    self.previous_fence_idx = image_idx;
+                    any_visible = true;
+                    self.draw_window(i);
                 }
+                *control_flow = if any_visible { ControlFlow::Poll } else { ControlFlow::Wait };
             }
             _ => (),
         });
     }
 
+    fn draw_window(&mut self, i: usize) {
+        if self.windows[i].swapchain_status != SwapchainStatus::Ok {
+            let window = &mut self.windows[i];
+            if !window.recreate_swapchain(self.instance.clone(), self.device.clone(), self.physical_device.clone()) {
+                return;
+            }
+        }
+        let window = &mut self.windows[i];
+        window.camera.set_viewport(&window.viewport);
+        let camera = window.camera.uniform();
+        let frame = match self.windows[i].acquire_frame(self.device.clone(), &camera) {
+            Some(frame) => frame,
+            None => return,
+        };
+        let command_buffer = self.record_scene(i, frame.image_idx as usize);
+        self.windows[i].present_frame(self.queues.graphics.clone(), frame, command_buffer);
+    }
+
+    // Applies new present mode, format and image count choices to the main window, recreating its swapchain right away
+    // While the window is minimized they are applied once it's restored
+    pub fn set_swapchain_config(&mut self, config: SwapchainConfig) {
+        self.set_window_swapchain_config(self.main_window_id(), config);
+    }
+
+    pub fn set_window_swapchain_config(&mut self, id: WindowId, config: SwapchainConfig) {
+        let i = self.window_index(id).expect("Unknown window");
+        let window = &mut self.windows[i];
+        window.swapchain_config = config;
+        window.swapchain_status = window.swapchain_status.merge(SwapchainStatus::NeedsRecreate);
+        window.recreate_swapchain(self.instance.clone(), self.device.clone(), self.physical_device.clone());
+    }
+
+    pub fn swapchain_config(&self) -> &SwapchainConfig {
+        &self.windows[0].swapchain_config
+    }
+
+    // Pipelines are created lazily for each program, state and render pass format in use
+    fn graphics_pipeline_for(&mut self, program: ProgramHandle, state: PipelineState, render_pass: &Arc<RenderPass>) -> Arc<GraphicsPipeline> {
+        let format = render_pass.attachments()[0].format;
+        self.graphics_pipelines.entry((program, state, format)).or_insert_with(|| {
+            pipeline::create_graphics_pipeline(self.device.clone(), &self.shaders, program, &state, render_pass.clone())
+        }).clone()
+    }
+
+    // Records the scene for one swapchain image of the window, every frame since the scene can change between them
+    // Opaque draws go first, grouped by program and material so each is bound once, then blended draws back to front
+    fn record_scene(&mut self, i: usize, image_idx: usize) -> Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>> {
+        let items = match self.windows[i].content {
+            WindowContent::Scene => self.scene.draw_items(),
+            WindowContent::Subtree(root) => self.scene.subtree_draw_items(root),
+        };
+        let mut draw_items: Vec<(MaterialHandle, DrawItem)> = items.into_iter()
+            .filter_map(|item| item.material.or(self.default_material).map(|material| (material, item)))
+            .collect();
+        // Blended surfaces are ordered by the view depth of their node's origin, so they composite over what's behind them
+        let view = self.windows[i].camera.view_matrix();
+        let view_depth = |item: &DrawItem| -view.transform_point3(item.world.w_axis.truncate()).z;
+        draw_items.sort_by(|(a, a_item), (b, b_item)| {
+            let (a_material, b_material) = (self.materials.get(*a), self.materials.get(*b));
+            let a_blended = a_material.state.blend != BlendMode::Opaque;
+            let b_blended = b_material.state.blend != BlendMode::Opaque;
+            a_blended.cmp(&b_blended).then_with(|| if a_blended {
+                view_depth(b_item).total_cmp(&view_depth(a_item))
+            } else {
+                (a_material.program(), *a).cmp(&(b_material.program(), *b))
+            })
+        });
+
+        let render_pass = self.windows[i].render_pass.clone();
+        let framebuffer = self.windows[i].framebuffers[image_idx].clone();
+        let viewport = self.windows[i].viewport.clone();
+        let camera_buffer = self.windows[i].camera_buffers[image_idx].clone();
+        let pipelines: Vec<Arc<GraphicsPipeline>> = draw_items.iter().map(|(material, _)| {
+            let (program, state) = (self.materials.get(*material).program(), self.materials.get(*material).state);
+            self.graphics_pipeline_for(program, state, &render_pass)
+        }).collect();
+        // Shadow passes are only recorded when some program samples them
+        let samples_shadows = pipelines.iter().any(|pipeline| {
+            pipeline.layout().set_layouts().get(CAMERA_SET as usize).is_some_and(|set_layout| {
+                set_layout.bindings().contains_key(&SHADOW_MAP_BINDING) || set_layout.bindings().contains_key(&SHADOW_LAYERS_BINDING)
+            })
+        });
+        let builder = buffer::create_command_buffer_builder(self.command_buffer_allocator.clone(), self.queues.graphics.clone());
+        let (builder, shadows) = if samples_shadows {
+            let (builder, shadows) = self.record_shadow_passes(builder, i, &draw_items);
+            (builder, Some(shadows))
+        } else {
+            (builder, None)
+        };
+        let lights = pbr::write_lights(&self.frame_allocator, &self.scene, shadows.as_ref().map_or(&[][..], |shadows| &shadows.plan.assignments));
+        let mut builder = pipeline::begin_render_pass(builder, framebuffer);
+        let mut bound_pipeline: Option<Arc<GraphicsPipeline>> = None;
+        let mut bound_material = None;
+        let mut bound_mesh: Option<Arc<Mesh>> = None;
+        for ((material, item), pipeline) in draw_items.into_iter().zip(pipelines) {
+            if !bound_pipeline.as_ref().is_some_and(|bound| Arc::ptr_eq(bound, &pipeline)) {
+                builder = pipeline::bind_graphics_pipeline(builder, pipeline.clone(), viewport.clone());
+                // Programs only get the camera and lights bound if they declare them
+                if let Some(set_layout) = pipeline.layout().set_layouts().get(CAMERA_SET as usize) {
+                    let mut writes = Vec::new();
+                    if set_layout.bindings().contains_key(&CAMERA_BINDING) {
+                        writes.push(WriteDescriptorSet::buffer(CAMERA_BINDING, camera_buffer.clone()));
+                    }
+                    if set_layout.bindings().contains_key(&LIGHTS_BINDING) {
+                        writes.push(WriteDescriptorSet::buffer(LIGHTS_BINDING, lights.clone()));
+                    }
+                    if let Some(shadows) = &shadows {
+                        if set_layout.bindings().contains_key(&SHADOW_LAYERS_BINDING) {
+                            writes.push(WriteDescriptorSet::buffer(SHADOW_LAYERS_BINDING, shadows.layers.clone()));
+                        }
+                        if set_layout.bindings().contains_key(&SHADOW_MAP_BINDING) {
+                            writes.push(WriteDescriptorSet::image_view_sampler(
+                                SHADOW_MAP_BINDING,
+                                shadows.view.clone(),
+                                shadows.sampler.clone()
+                            ));
+                        }
+                    }
+                    if !writes.is_empty() {
+                        let descriptor_set = PersistentDescriptorSet::new(&self.descriptor_set_allocator, set_layout.clone(), writes, [])
+                            .expect("Failed to create camera descriptor set");
+                        builder
+                            .bind_descriptor_sets(PipelineBindPoint::Graphics, pipeline.layout().clone(), CAMERA_SET, descriptor_set)
+                            .unwrap();
+                    }
+                }
+                bound_pipeline = Some(pipeline.clone());
+                bound_material = None;
+            }
+            if bound_material != Some(material) {
+                let params_size = self.shaders.uniform_block_size(
+                    self.materials.get(material).program(),
+                    MATERIAL_SET,
+                    MATERIAL_PARAMS_BINDING
+                );
+                let descriptor_set = self.materials.get_mut(material).descriptor_set(
+                    pipeline.layout(),
+                    params_size,
+                    self.memory_allocator.clone(),
+                    self.descriptor_set_allocator.clone()
+                );
+                if let Some(descriptor_set) = descriptor_set {
+                    builder
+                        .bind_descriptor_sets(PipelineBindPoint::Graphics, pipeline.layout().clone(), MATERIAL_SET, descriptor_set)
+                        .unwrap();
+                }
+                bound_material = Some(material);
+            }
+            // Only programs that declare a push constant block get the model matrix
+            if !pipeline.layout().push_constant_ranges().is_empty() {
+                builder
+                    .push_constants(pipeline.layout().clone(), 0, ModelPushConstants { model: item.world.to_cols_array_2d() })
+                    .unwrap();
+            }
+            if !bound_mesh.as_ref().is_some_and(|bound| Arc::ptr_eq(bound, &item.mesh)) {
+                builder = mesh::bind_mesh_buffers(builder, &item.mesh);
+                bound_mesh = Some(item.mesh.clone());
+            }
+            builder = mesh::record_submesh_draw(builder, &item.mesh.submeshes[item.submesh], 1);
+        }
+        let builder = pipeline::end_render_pass(builder);
+        buffer::build_command_buffer(builder)
+    }
+
+    // Renders each light's layers of the frame's shadow map with the opaque shadow casters, one pass per layer
+    fn record_shadow_passes(
+        &mut self,
+        mut builder: buffer::PrimaryCommandBufferBuilder,
+        i: usize,
+        draw_items: &[(MaterialHandle, DrawItem)]
+    ) -> (buffer::PrimaryCommandBufferBuilder, shadow::ShadowFrame) {
+        if self.shadows.is_none() {
+            self.shadows = Some(shadow::ShadowRenderer::new(self.device.clone(), &self.physical_device, &mut self.shaders, &self.shadow_config));
+        }
+        let shadows = self.shadows.as_ref().unwrap();
+        let plan = shadow::plan_shadows(&self.scene, &self.windows[i].camera, &self.shadow_config);
+        let layers = shadow::write_shadow_layers(&self.frame_allocator, &plan, &self.shadow_config);
+        let shadow_map = self.windows[i].current_shadow_map(&shadows.render_pass, &self.shadow_config);
+        let layer_count = if shadow_map.initialized { plan.layers.len() } else { shadow_map.framebuffers.len() };
+        shadow_map.initialized = true;
+        let resolution = self.shadow_config.resolution as f32;
+        let viewport = Viewport {
+            offset: [0.0, 0.0],
+            extent: [resolution, resolution],
+            depth_range: 0.0..=1.0,
+        };
+        for (layer, framebuffer) in shadow_map.framebuffers.iter().enumerate().take(layer_count) {
+            builder = pipeline::begin_render_pass_with(builder, framebuffer.clone(), vec![Some(1.0.into())], &format!("shadow layer {}", layer));
+            if let Some(shadow_layer) = plan.layers.get(layer) {
+                builder = pipeline::bind_graphics_pipeline(builder, shadows.pipeline.clone(), viewport.clone());
+                let mut bound_mesh: Option<Arc<Mesh>> = None;
+                for (material, item) in draw_items {
+                    // Blended surfaces let the light through
+                    if !item.cast_shadows || self.materials.get(*material).state.blend != BlendMode::Opaque {
+                        continue;
+                    }
+                    builder
+                        .push_constants(shadows.pipeline.layout().clone(), 0, shadow::ShadowPushConstants {
+                            model: item.world.to_cols_array_2d(),
+                            light_view_projection: shadow_layer.view_projection,
+                        })
+                        .unwrap();
+                    if !bound_mesh.as_ref().is_some_and(|bound| Arc::ptr_eq(bound, &item.mesh)) {
+                        builder = mesh::bind_mesh_buffers(builder, &item.mesh);
+                        bound_mesh = Some(item.mesh.clone());
+                    }
+                    builder = mesh::record_submesh_draw(builder, &item.mesh.submeshes[item.submesh], 1);
+                }
+            }
+            builder = pipeline::end_render_pass(builder);
+        }
+        let view = shadow_map.view.clone();
+        (builder, shadow::ShadowFrame { plan, layers, view, sampler: shadows.sampler.clone() })
+    }
+
     // pub fn fractal_sample(&self) {
     //     let image = image::create_image(self.memory_allocator.clone(), Format::R8G8B8A8_UNORM, ImageUsage::TRANSFER_DST | ImageUsage::TRANSFER_SRC, ImageType::Dim2d, [1024, 1024, 1]);
     //     let image_view = image::create_image_view(image.clone(), Format::R8G8B8A8_UNORM);
@@ -275,80 +773,65 @@ impl VkApp {
     //     let command_buffer = image::clear_image(command_buffer, image.clone(), [0.0, 0.0, 0.0, 1.0]);
     // }
 
-    pub fn triangle_sample(&mut self) {
-        let vertices = vec![
-            Vert { position: [-0.5, -0.5, 0.0]}, // UL
-            Vert { position: [0.5, -0.5, 0.0]}, // UR
-            Vert { position: [0.5, 0.5, 0.0]}, // BR
-            Vert { position: [-0.5, 0.5, 0.0]}, // BL
-        ];
-
-        let indices: Vec<u32> = vec![
-            0, 1, 2,
-            2, 3, 0,
-        ];
-
-        let num_vertices = indices.len() as u32/3;
-
-        let vertex_buffer = buffer::create_buffer_from_iter(
-            self.memory_allocator.clone(), 
-            buffer::UNIFORM_BUFFER_MEMORY_TYPE_FILTER, 
-            BufferUsage::VERTEX_BUFFER, 
-            vertices.into_iter()
-        );
-
-        let index_buffer = buffer::create_index_buffer(
-            self.memory_allocator.clone(), 
-            indices.into_iter()
-        );
+    pub fn create_mesh(&self, name: &str, vertices: &[Vert], indices: &[u32], submeshes: Vec<Submesh>) -> Arc<Mesh> {
+        Mesh::new(self.memory_allocator.clone(), name, vertices, indices, submeshes)
+    }
 
-        let vertex_buffer = Arc::new(vertex_buffer);
+    // Wavefront OBJ with its MTL materials, one submesh per object in the file
+    pub fn load_obj(&self, path: impl AsRef<Path>) -> ObjModel {
+        obj::load_obj(self.memory_allocator.clone(), path)
+    }
 
-        self.shaders.load_shader_from_file("shaders/vert.vs", "vertex");
-        self.shaders.load_shader_from_file("shaders/frag.fs", "fragment"); 
+    // Textures are uploaded on the graphics queue
+    pub fn load_gltf(&self, path: impl AsRef<Path>, options: &GltfImportOptions) -> GltfScene {
+        gltf_import::load_gltf(
+            self.memory_allocator.clone(),
+            self.command_buffer_allocator.clone(),
+            self.queues.graphics.clone(),
+            path,
+            options
+        )
+    }
 
-        let pipeline = pipeline::create_graphics_pipeline(self.device.clone(), &self.shaders, self.viewport.clone(), self.render_pass.clone());
-        self.graphics_pipeline = Some(pipeline);
+    pub fn triangle_sample(&mut self) {
+        let quad = primitives::quad(1.0, 1.0);
+        let mesh = self.create_mesh("quad", &quad.vertices, &quad.indices, Vec::new());
 
-        let mut new_command_buffers = Vec::new();
-        for framebuffer in &self.framebuffers {
-            let command_buffer_builder = buffer::create_command_buffer_builder(
-                self.command_buffer_allocator.clone(),
-                self.queue.clone()
-            );
-            let command_buffer_builder = pipeline::record_render_pass(
-                command_buffer_builder,
-                self.render_pass.clone(),
-                framebuffer.clone(),
-                self.graphics_pipeline.as_ref().unwrap().clone(),
-                0,
-                vertex_buffer.clone(),
-                index_buffer.clone(),
-                num_vertices,
-                num_vertices / 3,
-                0,
-                0
-            );
-            let command_buffer = buffer::build_command_buffer(command_buffer_builder);
-            new_command_buffers.push(command_buffer);
-        }
+        let program = self.load_program("triangle", "shaders/vert.vs", "shaders/frag.fs");
+        let material = self.add_material(Material::new("triangle", program));
+        self.set_default_material(Some(material));
 
-        // buffer::submit_execute_wait_fenced(self.device.clone(), self.queue.clone(), command_buffer.clone()); The present call runs then_execute which executes the command buffer
+        // buffer::submit_execute_wait_fenced(self.device.clone(), self.queues.graphics.clone(), command_buffer.clone()); The present call runs then_execute which executes the command buffer
 
-        self.vertex_buffer = Some(vertex_buffer.clone());
-        self.index_buffer = Some(index_buffer.clone());
-        self.command_buffers = Some(new_command_buffers);
+        self.scene.add(Node::new("quad").with_mesh(mesh, Vec::new()), None);
     }   
 }
 
 
-fn create_instance(required_extensions: InstanceExtensions) -> Arc<Instance> {
+// In debug mode the validation layer and ext_debug_utils are enabled when the loader has them
+fn create_instance(required_extensions: InstanceExtensions, debug_config: &DebugConfig) -> Arc<Instance> {
     let library = VulkanLibrary::new().expect("Failed to load Vulkan library");
+    let enabled_layers = debug::instance_layers(&library, debug_config);
+    let mut enabled_extensions = required_extensions;
+    // Needed for the surface to report HDR and extended color spaces
+    if library.supported_extensions().ext_swapchain_colorspace {
+        enabled_extensions.ext_swapchain_colorspace = true;
+    }
+    if debug_config.enabled {
+        let supported = library.supported_extensions_with_layers(enabled_layers.iter().map(String::as_str))
+            .expect("Failed to query instance extensions");
+        if supported.ext_debug_utils {
+            enabled_extensions.ext_debug_utils = true;
+        } else {
+            log::warn!("ext_debug_utils not available, debug messages won't be reported");
+        }
+    }
     let instance = Instance::new(
         library, 
         InstanceCreateInfo {
             flags: InstanceCreateFlags::ENUMERATE_PORTABILITY,
-            enabled_extensions: required_extensions,
+            enabled_layers,
+            enabled_extensions,
             ..Default::default()
         }
     ).expect("Failed to create instance");
@@ -0,0 +1,134 @@
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::vk::mesh::{self, Mesh, Submesh};
+use crate::vk::Vert;
+
+// What the MTL file describes, texture paths are resolved relative to the OBJ file
+#[derive(Clone, Debug)]
+pub struct ObjMaterial {
+    pub name: String,
+    pub ambient: [f32; 3],
+    pub diffuse: [f32; 3],
+    pub specular: [f32; 3],
+    pub shininess: f32,
+    // 1 is opaque
+    pub dissolve: f32,
+    pub diffuse_texture: Option<PathBuf>,
+    pub specular_texture: Option<PathBuf>,
+    pub normal_texture: Option<PathBuf>,
+}
+
+impl Default for ObjMaterial {
+    fn default() -> Self {
+        ObjMaterial {
+            name: "default".to_string(),
+            ambient: [0.0; 3],
+            diffuse: [0.8; 3],
+            specular: [0.0; 3],
+            shininess: 0.0,
+            dissolve: 1.0,
+            diffuse_texture: None,
+            specular_texture: None,
+            normal_texture: None,
+        }
+    }
+}
+
+impl ObjMaterial {
+    fn from_mtl(material: tobj::Material, directory: &Path) -> Self {
+        let texture = |path: Option<String>| path.map(|path| directory.join(path));
+        let default = ObjMaterial::default();
+        ObjMaterial {
+            name: material.name,
+            ambient: material.ambient.unwrap_or(default.ambient),
+            diffuse: material.diffuse.unwrap_or(default.diffuse),
+            specular: material.specular.unwrap_or(default.specular),
+            shininess: material.shininess.unwrap_or(default.shininess),
+            dissolve: material.dissolve.unwrap_or(default.dissolve),
+            diffuse_texture: texture(material.diffuse_texture),
+            specular_texture: texture(material.specular_texture),
+            normal_texture: texture(material.normal_texture),
+        }
+    }
+}
+
+// Submesh material slots index into materials
+pub struct ObjModel {
+    pub mesh: Arc<Mesh>,
+    pub materials: Vec<ObjMaterial>,
+}
+
+// Faces are triangulated and vertices sharing the same position, normal and uv are merged
+// Objects without a material, or whose MTL file failed to load, get a default one appended to the materials
+pub fn load_obj(memory_allocator: Arc<StandardMemoryAllocator>, path: impl AsRef<Path>) -> ObjModel {
+    let path = path.as_ref();
+    let (models, mtl_result) = tobj::load_obj(path, &tobj::LoadOptions {
+        single_index: true,
+        triangulate: true,
+        ignore_points: true,
+        ignore_lines: true,
+    }).unwrap_or_else(|e| panic!("Failed to load OBJ `{}`: {}", path.display(), e));
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let mut materials: Vec<ObjMaterial> = match mtl_result {
+        Ok(materials) => materials.into_iter().map(|material| ObjMaterial::from_mtl(material, directory)).collect(),
+        Err(e) => {
+            log::warn!("Failed to load materials of `{}`: {}", path.display(), e);
+            Vec::new()
+        }
+    };
+    let mut default_slot = None;
+
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut submeshes = Vec::new();
+    for model in models {
+        let mesh = model.mesh;
+        if mesh.indices.is_empty() {
+            continue;
+        }
+        let first_vertex = vertices.len() as u32;
+        let first_index = indices.len() as u32;
+        let vertex_count = mesh.positions.len() / 3;
+        let has_normals = mesh.normals.len() == mesh.positions.len();
+        let has_uvs = mesh.texcoords.len() / 2 == vertex_count;
+        for i in 0..vertex_count {
+            vertices.push(Vert {
+                position: [mesh.positions[i * 3], mesh.positions[i * 3 + 1], mesh.positions[i * 3 + 2]],
+                normal: if has_normals {
+                    [mesh.normals[i * 3], mesh.normals[i * 3 + 1], mesh.normals[i * 3 + 2]]
+                } else {
+                    [0.0; 3]
+                },
+                // OBJ puts the uv origin at the bottom left
+                uv: if has_uvs { [mesh.texcoords[i * 2], 1.0 - mesh.texcoords[i * 2 + 1]] } else { [0.0; 2] },
+                tangent: [0.0; 4],
+            });
+        }
+        indices.extend(mesh.indices.iter().map(|index| index + first_vertex));
+        if !has_normals {
+            mesh::compute_normals(&mut vertices[first_vertex as usize..], &mesh.indices);
+        }
+        mesh::compute_tangents(&mut vertices[first_vertex as usize..], &mesh.indices);
+
+        let material_slot = match mesh.material_id.filter(|&id| id < materials.len()) {
+            Some(id) => id,
+            None => *default_slot.get_or_insert_with(|| {
+                materials.push(ObjMaterial::default());
+                materials.len() - 1
+            }),
+        };
+        submeshes.push(Submesh::new(first_index..indices.len() as u32, material_slot));
+    }
+    if submeshes.is_empty() {
+        panic!("OBJ `{}` has no faces", path.display());
+    }
+
+    let name = path.file_stem().map_or("obj".into(), |stem| stem.to_string_lossy());
+    ObjModel {
+        mesh: Mesh::new(memory_allocator, &name, &vertices, &indices, submeshes),
+        materials,
+    }
+}
@@ -0,0 +1,234 @@
+use glam::{Vec3, Vec4};
+use vulkano::buffer::{BufferContents, Subbuffer};
+use vulkano::buffer::allocator::SubbufferAllocator;
+use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+use vulkano::device::Queue;
+use vulkano::format::Format;
+use vulkano::image::sampler::{Sampler, SamplerCreateInfo};
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use std::sync::Arc;
+
+use crate::vk::gltf_import::{AlphaMode, GltfMaterial};
+use crate::vk::image::{self, Texture};
+use crate::vk::material::{Material, MaterialValue};
+use crate::vk::pipeline::{FaceCulling, PipelineState};
+use crate::vk::scene::{Light, LightKind, Scene};
+use crate::vk::shader::{ProgramHandle, ShaderLanguage, ShaderProgram, ShaderStage, Shaders};
+
+// Storage buffer with the scene's lights, in the camera set so every lit program sees the same list
+pub const LIGHTS_BINDING: u32 = 1;
+
+pub const BASE_COLOR_BINDING: u32 = 1;
+pub const METALLIC_ROUGHNESS_BINDING: u32 = 2;
+pub const NORMAL_BINDING: u32 = 3;
+pub const OCCLUSION_BINDING: u32 = 4;
+pub const EMISSIVE_BINDING: u32 = 5;
+
+// Matches the Light struct of shaders/pbr.frag under std430
+#[derive(BufferContents, Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct GpuLight {
+    pub position: [f32; 3],
+    // 0 means no cutoff
+    pub range: f32,
+    pub direction: [f32; 3],
+    pub kind: u32,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub inner_cone_cos: f32,
+    pub outer_cone_cos: f32,
+    // First layer in the shadow maps, -1 for unshadowed lights
+    pub shadow_layer: i32,
+    pub shadow_layer_count: u32,
+}
+
+#[derive(BufferContents)]
+#[repr(C)]
+pub struct LightList {
+    pub ambient: [f32; 3],
+    pub count: u32,
+    // Never empty since buffers can't be, count says how many are in use
+    pub lights: [GpuLight],
+}
+
+impl GpuLight {
+    // Lights point down the node's -Z, scale is ignored
+    fn new(light: &Light, world: glam::Mat4, shadow: Option<(u32, u32)>) -> Self {
+        let (kind, inner_cone_cos, outer_cone_cos) = match light.kind {
+            LightKind::Directional => (0, 0.0, 0.0),
+            LightKind::Point => (1, 0.0, 0.0),
+            LightKind::Spot { inner_cone_angle, outer_cone_angle } => {
+                let outer_cone_cos = outer_cone_angle.cos();
+                // smoothstep needs the inner cone strictly inside the outer one
+                (2, inner_cone_angle.cos().max(outer_cone_cos + 0.0001), outer_cone_cos)
+            }
+        };
+        GpuLight {
+            position: world.w_axis.truncate().to_array(),
+            range: light.range.unwrap_or(0.0),
+            direction: world.transform_vector3(Vec3::NEG_Z).normalize_or_zero().to_array(),
+            kind,
+            color: light.color,
+            intensity: light.intensity,
+            inner_cone_cos,
+            outer_cone_cos,
+            shadow_layer: shadow.map_or(-1, |(first, _)| first as i32),
+            shadow_layer_count: shadow.map_or(0, |(_, count)| count),
+        }
+    }
+}
+
+// Written every frame from the scene's lights as of its last update_world_transforms
+// shadows has the shadow map layers of each light in Scene::lights order, see ShadowPlan
+pub fn write_lights(allocator: &SubbufferAllocator, scene: &Scene, shadows: &[Option<(u32, u32)>]) -> Subbuffer<LightList> {
+    let lights: Vec<GpuLight> = scene.lights()
+        .enumerate()
+        .map(|(i, (light, world))| GpuLight::new(light, world, shadows.get(i).copied().flatten()))
+        .collect();
+    let buffer = allocator.allocate_unsized::<LightList>(lights.len().max(1) as u64)
+        .expect("Failed to allocate light buffer");
+    {
+        let mut list = buffer.write().expect("Light buffer still in use");
+        list.ambient = scene.ambient;
+        list.count = lights.len() as u32;
+        list.lights[..lights.len()].copy_from_slice(&lights);
+    }
+    buffer
+}
+
+// Metallic-roughness parameters of the built-in PBR program, missing textures read as white, or as a flat normal
+#[derive(Clone)]
+pub struct PbrParams {
+    // Linear, multiplied with the base color texture
+    pub base_color: Vec4,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub emissive: Vec3,
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32,
+    pub double_sided: bool,
+    // Sampled as sRGB
+    pub base_color_texture: Option<Texture>,
+    // Metalness in blue, roughness in green
+    pub metallic_roughness_texture: Option<Texture>,
+    pub normal_texture: Option<Texture>,
+    pub occlusion_texture: Option<Texture>,
+    // Sampled as sRGB
+    pub emissive_texture: Option<Texture>,
+}
+
+impl Default for PbrParams {
+    fn default() -> Self {
+        PbrParams {
+            base_color: Vec4::ONE,
+            metallic: 0.0,
+            roughness: 0.5,
+            emissive: Vec3::ZERO,
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            double_sided: false,
+            base_color_texture: None,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive_texture: None,
+        }
+    }
+}
+
+impl PbrParams {
+    // textures are the GltfScene's, which the material's texture indices point into
+    pub fn from_gltf(material: &GltfMaterial, textures: &[Texture]) -> Self {
+        let texture = |index: Option<usize>| index.map(|i| textures[i].clone());
+        PbrParams {
+            base_color: Vec4::from(material.base_color_factor),
+            metallic: material.metallic_factor,
+            roughness: material.roughness_factor,
+            emissive: Vec3::from(material.emissive_factor),
+            normal_scale: material.normal_scale,
+            occlusion_strength: material.occlusion_strength,
+            alpha_mode: material.alpha_mode,
+            alpha_cutoff: material.alpha_cutoff,
+            double_sided: material.double_sided,
+            base_color_texture: texture(material.base_color_texture),
+            metallic_roughness_texture: texture(material.metallic_roughness_texture),
+            normal_texture: texture(material.normal_texture),
+            occlusion_texture: texture(material.occlusion_texture),
+            emissive_texture: texture(material.emissive_texture),
+        }
+    }
+
+    // In the order the built-in program's Material block declares them
+    pub(crate) fn params(&self) -> [(&'static str, MaterialValue); 7] {
+        let alpha_cutoff = if self.alpha_mode == AlphaMode::Mask { self.alpha_cutoff } else { -1.0 };
+        [
+            ("base_color_factor", MaterialValue::Vec4(self.base_color)),
+            ("emissive_factor", MaterialValue::Vec3(self.emissive)),
+            ("metallic_factor", MaterialValue::Float(self.metallic)),
+            ("roughness_factor", MaterialValue::Float(self.roughness)),
+            ("normal_scale", MaterialValue::Float(self.normal_scale)),
+            ("occlusion_strength", MaterialValue::Float(self.occlusion_strength)),
+            ("alpha_cutoff", MaterialValue::Float(alpha_cutoff)),
+        ]
+    }
+
+    pub fn to_material(&self, name: &str, resources: &PbrResources) -> Material {
+        let state = PipelineState {
+            cull: if self.double_sided { FaceCulling::None } else { FaceCulling::Back },
+            ..match self.alpha_mode {
+                AlphaMode::Blend => PipelineState::transparent(),
+                _ => PipelineState::default(),
+            }
+        };
+        let or_default = |texture: &Option<Texture>, default: &Texture| texture.clone().unwrap_or_else(|| default.clone());
+        self.params().into_iter()
+            .fold(Material::new(name, resources.program).with_state(state), |material, (param, value)| material.with_param(param, value))
+            .with_texture(BASE_COLOR_BINDING, or_default(&self.base_color_texture, &resources.white))
+            .with_texture(METALLIC_ROUGHNESS_BINDING, or_default(&self.metallic_roughness_texture, &resources.white))
+            .with_texture(NORMAL_BINDING, or_default(&self.normal_texture, &resources.flat_normal))
+            .with_texture(OCCLUSION_BINDING, or_default(&self.occlusion_texture, &resources.white))
+            .with_texture(EMISSIVE_BINDING, or_default(&self.emissive_texture, &resources.white))
+    }
+}
+
+// The built-in program and the 1x1 textures standing in for missing ones
+pub struct PbrResources {
+    pub program: ProgramHandle,
+    pub white: Texture,
+    pub flat_normal: Texture,
+}
+
+impl PbrResources {
+    pub fn new(
+        shaders: &mut Shaders,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        command_buffer_allocator: Arc<StandardCommandBufferAllocator>,
+        queue: Arc<Queue>
+    ) -> Self {
+        let vertex = shaders.load_shader_from_string(include_str!("shaders/pbr.vert"), ShaderLanguage::Glsl, ShaderStage::Vertex, "main");
+        let fragment = shaders.load_shader_from_string(include_str!("shaders/pbr.frag"), ShaderLanguage::Glsl, ShaderStage::Fragment, "main");
+        let program = shaders.register_program("pbr", ShaderProgram::graphics(vertex, fragment));
+        let sampler = Sampler::new(queue.device().clone(), SamplerCreateInfo::simple_repeat_linear())
+            .expect("Failed to create sampler");
+        let create_pixel = |pixel: [u8; 4]| Texture {
+            view: image::create_texture(
+                memory_allocator.clone(),
+                command_buffer_allocator.clone(),
+                queue.clone(),
+                Format::R8G8B8A8_UNORM,
+                [1, 1],
+                &pixel
+            ),
+            sampler: sampler.clone(),
+        };
+        PbrResources {
+            program,
+            white: create_pixel([255, 255, 255, 255]),
+            flat_normal: create_pixel([128, 128, 255, 255]),
+        }
+    }
+}
@@ -1,15 +1,16 @@
 use std::sync::Arc;
 
-use vulkano::pipeline::{Pipeline, ComputePipeline, PipelineLayout, PipelineShaderStageCreateInfo, PipelineBindPoint};
+use vulkano::pipeline::{Pipeline, DynamicState, ComputePipeline, PipelineLayout, PipelineShaderStageCreateInfo, PipelineBindPoint};
 use vulkano::pipeline::compute::ComputePipelineCreateInfo;
 use vulkano::pipeline::layout::PipelineDescriptorSetLayoutCreateInfo;
 use vulkano::pipeline::graphics::{GraphicsPipeline, GraphicsPipelineCreateInfo};
 use vulkano::pipeline::graphics::vertex_input::{Vertex, VertexDefinition};
 use vulkano::pipeline::graphics::input_assembly::InputAssemblyState;
 use vulkano::pipeline::graphics::viewport::{Viewport, ViewportState};
-use vulkano::pipeline::graphics::rasterization::RasterizationState;
+use vulkano::pipeline::graphics::rasterization::{RasterizationState, CullMode, FrontFace, DepthBiasState};
+use vulkano::pipeline::graphics::depth_stencil::{DepthStencilState, DepthState, CompareOp};
 use vulkano::pipeline::graphics::multisample::MultisampleState;
-use vulkano::pipeline::graphics::color_blend::{ColorBlendState, ColorBlendAttachmentState};
+use vulkano::pipeline::graphics::color_blend::{ColorBlendState, ColorBlendAttachmentState, AttachmentBlend};
 use vulkano::descriptor_set::{PersistentDescriptorSet, WriteDescriptorSet};
 use vulkano::descriptor_set::allocator::StandardDescriptorSetAllocator;
 use vulkano::buffer::{Buffer, Subbuffer, BufferContents, IndexBuffer};
@@ -19,25 +20,29 @@ use vulkano::command_buffer::{
     RenderPassBeginInfo, SubpassBeginInfo, SubpassContents, SubpassEndInfo,
 };
 
-use vulkano::image::Image;
+use vulkano::image::{Image, ImageLayout, ImageType, ImageUsage};
+use vulkano::memory::allocator::StandardMemoryAllocator;
 use vulkano::image::view::ImageView;
-use vulkano::format::{Format, ClearColorValue};
+use vulkano::format::{Format, ClearColorValue, ClearValue};
 
 use vulkano::shader::ShaderModule;
 use vulkano::device::Device;
 use vulkano::command_buffer::{AutoCommandBufferBuilder, PrimaryAutoCommandBuffer};
 use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
 
-use crate::vk::shader::Shaders;
-use crate::vk::image::create_image_view;
+use crate::vk::shader::{Shaders, ShaderProgram, ProgramStage, ProgramHandle, SpecializationConstants};
+use crate::vk::image::{create_image, create_image_view};
 use crate::vk::buffer::PrimaryCommandBufferBuilder;
 use crate::vk::Vert;
+use crate::vk::debug;
+use crate::vk::mesh::{self, Mesh};
 pub struct Pipe {
     pub pipeline: Option<Arc<dyn Pipeline>>,
     pub layout: Option<Arc<PipelineLayout>>,
 }
 
-pub fn record_compute_pipeline(mut builder: PrimaryCommandBufferBuilder, pipeline: Arc<ComputePipeline>, set_index: u32, descriptor_set: Arc<PersistentDescriptorSet>, work_group_counts: [u32; 3]) -> PrimaryCommandBufferBuilder {
+pub fn record_compute_pipeline(builder: PrimaryCommandBufferBuilder, pipeline: Arc<ComputePipeline>, set_index: u32, descriptor_set: Arc<PersistentDescriptorSet>, work_group_counts: [u32; 3]) -> PrimaryCommandBufferBuilder {
+    let mut builder = debug::begin_label(builder, &format!("dispatch {:?}", work_group_counts), [0.0; 4]);
     builder
         .bind_pipeline_compute(pipeline.clone())
         .unwrap()
@@ -49,27 +54,32 @@ pub fn record_compute_pipeline(mut builder: PrimaryCommandBufferBuilder, pipelin
         .unwrap()
         .dispatch(work_group_counts)
         .unwrap();
-    builder
+    debug::end_label(builder)
+}
+
+pub fn create_compute_pipeline(device: Arc<Device>, shaders: &Shaders, program: ProgramHandle) -> Arc<ComputePipeline> {
+    create_compute_pipeline_specialized(device, shaders, program, &SpecializationConstants::default())
 }
 
-pub fn create_compute_pipeline(device: Arc<Device>, shaders: &Shaders) -> Arc<ComputePipeline> {
-    let (pipeline_layout, shader_stages) = create_pipeline_layout(device.clone(), shaders);
-    let stage = create_pipeline_stage_from_shader(shaders.compute.clone().unwrap());
+pub fn create_compute_pipeline_specialized(device: Arc<Device>, shaders: &Shaders, program: ProgramHandle, specialization: &SpecializationConstants) -> Arc<ComputePipeline> {
+    let name = shaders.program_name(program);
+    let program = shaders.program(program);
+    let (pipeline_layout, mut shader_stages) = create_pipeline_layout(device.clone(), program, specialization);
+    if !program.is_compute() {
+        panic!("Compute pipelines need a compute program");
+    }
+    let stage = shader_stages.remove(0);
     let compute_pipeline = ComputePipeline::new(device.clone(), None, ComputePipelineCreateInfo::stage_layout(stage, pipeline_layout)).expect("Failed to create compute pipeline");
+    debug::set_object_name(&*compute_pipeline, &format!("{} compute pipeline", name));
     compute_pipeline
 }
 
-pub fn create_pipeline_layout(device: Arc<Device>, shaders: &Shaders) -> (Arc<PipelineLayout>, Vec<PipelineShaderStageCreateInfo>) {
-    let mut shader_stages: Vec<PipelineShaderStageCreateInfo> = Vec::new();
-    if let Some(vertex) = shaders.vertex.clone() {
-        shader_stages.push(create_pipeline_stage_from_shader(vertex));
-    }
-    if let Some(fragment) = shaders.fragment.clone() {
-        shader_stages.push(create_pipeline_stage_from_shader(fragment));
-    }
-    if let Some(compute) = shaders.compute.clone() {
-        shader_stages.push(create_pipeline_stage_from_shader(compute));
-    }
+// Only the stages of the given program end up in the layout
+pub fn create_pipeline_layout(device: Arc<Device>, program: &ShaderProgram, specialization: &SpecializationConstants) -> (Arc<PipelineLayout>, Vec<PipelineShaderStageCreateInfo>) {
+    specialization.check_names(program);
+    let shader_stages: Vec<PipelineShaderStageCreateInfo> = program.stages()
+        .map(|stage| create_pipeline_stage_from_shader(stage, specialization))
+        .collect();
 
     (
         PipelineLayout::new(
@@ -82,9 +92,12 @@ pub fn create_pipeline_layout(device: Arc<Device>, shaders: &Shaders) -> (Arc<Pi
     )
 }
 
-pub fn create_pipeline_stage_from_shader(shader: Arc<ShaderModule>) -> PipelineShaderStageCreateInfo {
-    let stage = PipelineShaderStageCreateInfo::new(shader.entry_point("main").unwrap());
-    stage
+pub fn create_pipeline_stage_from_shader(stage: &ProgramStage, specialization: &SpecializationConstants) -> PipelineShaderStageCreateInfo {
+    let entry_point = stage.module.specialize(specialization.for_stage(stage).into_iter().collect())
+        .expect("Invalid specialization constant value")
+        .entry_point(&stage.entry_point)
+        .unwrap_or_else(|| panic!("Entry point `{}` not found in shader module", stage.entry_point));
+    PipelineShaderStageCreateInfo::new(entry_point)
 }
 
 pub fn create_descriptor_set_from_buffer<T: BufferContents>(pipeline_layout: Arc<PipelineLayout>, descriptor_set_allocator: Arc<StandardDescriptorSetAllocator>, set_index: usize, binding_index: usize, buffer: Subbuffer<T>) -> Arc<PersistentDescriptorSet> {
@@ -98,7 +111,7 @@ pub fn create_descriptor_set_from_buffer<T: BufferContents>(pipeline_layout: Arc
     descriptor_set
 }
 
-pub fn create_render_pass(device: Arc<Device>, image_format: Format) -> Arc<RenderPass> {
+pub fn create_render_pass(device: Arc<Device>, image_format: Format, depth_format: Format) -> Arc<RenderPass> {
     let render_pass = vulkano::single_pass_renderpass!(
         device.clone(),
         attachments: {
@@ -107,42 +120,101 @@ pub fn create_render_pass(device: Arc<Device>, image_format: Format) -> Arc<Rend
                 samples: 1,
                 load_op: Clear,
                 store_op: Store, // Might be more efficient to have store_op: DontCare
+            },
+            depth: {
+                format: depth_format,
+                samples: 1,
+                load_op: Clear,
+                store_op: DontCare,
             }
         },
         pass: {
             color: [c],
-            depth_stencil: {},
+            depth_stencil: {depth},
         },
     ).expect("Failed to create render pass");
     render_pass
 }
 
-pub fn create_framebuffers(render_pass: Arc<RenderPass>, images: Vec<Arc<Image>>) -> Vec<Arc<Framebuffer>> {
+// Depth only pass rendering from a light, left ready to be sampled by the lighting pass
+pub fn create_shadow_render_pass(device: Arc<Device>, depth_format: Format) -> Arc<RenderPass> {
+    let render_pass = vulkano::single_pass_renderpass!(
+        device.clone(),
+        attachments: {
+            depth: {
+                format: depth_format,
+                samples: 1,
+                load_op: Clear,
+                store_op: Store,
+                initial_layout: ImageLayout::Undefined,
+                final_layout: ImageLayout::ShaderReadOnlyOptimal,
+            }
+        },
+        pass: {
+            color: [],
+            depth_stencil: {depth},
+        },
+    ).expect("Failed to create shadow render pass");
+    render_pass
+}
+
+// Every framebuffer gets its own depth image so frames in flight don't share one
+pub fn create_framebuffers(memory_allocator: Arc<StandardMemoryAllocator>, render_pass: Arc<RenderPass>, images: Vec<Arc<Image>>) -> Vec<Arc<Framebuffer>> {
+    let depth_format = render_pass.attachments()[1].format;
     images.iter().map(|image| {
+        let [width, height, _] = image.extent();
+        let depth_image = create_image(
+            memory_allocator.clone(),
+            depth_format,
+            ImageUsage::DEPTH_STENCIL_ATTACHMENT | ImageUsage::TRANSIENT_ATTACHMENT,
+            ImageType::Dim2d,
+            [width, height, 1]
+        );
         Framebuffer::new(render_pass.clone(), FramebufferCreateInfo{
-            attachments: vec![create_image_view(image.clone(), image.format())],
+            attachments: vec![
+                create_image_view(image.clone(), image.format()),
+                create_image_view(depth_image.clone(), depth_format),
+            ],
             ..Default::default()
         }).expect("Failed to create framebuffer")
     }).collect::<Vec<_>>()
 }
 
-pub fn record_render_pass<T: BufferContents + ?Sized>(
-    mut builder: PrimaryCommandBufferBuilder, 
+pub fn record_render_pass(
+    builder: PrimaryCommandBufferBuilder, 
     render_pass: Arc<RenderPass>,
     framebuffer: Arc<Framebuffer>, 
+    viewport: Viewport,
     pipeline: Arc<GraphicsPipeline>, 
     set_index: u32, 
-    // descriptor_set: Option<Arc<PersistentDescriptorSet>>, 
-    vertex_buffer: Arc<Subbuffer<T>>, 
-    index_buffer: Arc<IndexBuffer>,
-    vertex_count: u32, 
-    instance_count: u32, 
-    first_vertex: u32, 
-    first_instance: u32) -> PrimaryCommandBufferBuilder {
+    descriptor_set: Option<Arc<PersistentDescriptorSet>>, 
+    mesh: &Mesh,
+    instance_count: u32) -> PrimaryCommandBufferBuilder {
+    let mut builder = begin_render_pass(builder, framebuffer);
+    builder = bind_graphics_pipeline(builder, pipeline.clone(), viewport);
+    if let Some(descriptor_set) = descriptor_set {
+        builder
+            .bind_descriptor_sets(PipelineBindPoint::Graphics, pipeline.layout().clone(), set_index, descriptor_set)
+            .unwrap();
+    }
+    let builder = mesh::record_mesh_draw(builder, mesh, instance_count);
+    end_render_pass(builder)
+}
+
+// Opens a debug label that end_render_pass closes, so draws recorded in between show up under it
+pub fn begin_render_pass(builder: PrimaryCommandBufferBuilder, framebuffer: Arc<Framebuffer>) -> PrimaryCommandBufferBuilder {
+    let [width, height] = framebuffer.extent();
+    let label = format!("render pass {}x{}", width, height);
+    begin_render_pass_with(builder, framebuffer, vec![Some([1.0, 1.0, 1.0, 1.0].into()), Some(1.0.into())], &label) // Only blue for now... 
+}
+
+// Clear values in attachment order
+pub fn begin_render_pass_with(builder: PrimaryCommandBufferBuilder, framebuffer: Arc<Framebuffer>, clear_values: Vec<Option<ClearValue>>, label: &str) -> PrimaryCommandBufferBuilder {
+    let mut builder = debug::begin_label(builder, label, [0.0; 4]);
     builder
         .begin_render_pass(
             RenderPassBeginInfo{
-                clear_values: vec![Some([1.0, 1.0, 1.0, 1.0].into())], // Only blue for now... 
+                clear_values,
                 ..RenderPassBeginInfo::framebuffer(framebuffer.clone())
             },
             SubpassBeginInfo{
@@ -150,30 +222,112 @@ pub fn record_render_pass<T: BufferContents + ?Sized>(
                 ..Default::default()    
             },
         )
-        .unwrap()
-        .bind_pipeline_graphics(pipeline.clone())
-        .unwrap()
-        .bind_vertex_buffers(0, (*vertex_buffer).clone())
-        .unwrap()
-        .bind_index_buffer((*index_buffer).clone())
-        .unwrap()
-        // .bind_descriptor_sets(PipelineBindPoint::Graphics, pipeline.layout().clone(), set_index, descriptor_set.clone())
-        // .unwrap()
-        .draw_indexed(vertex_count, instance_count, 0, first_vertex as i32, first_instance)
-        .unwrap()
+        .unwrap();
+    builder
+}
+
+pub fn end_render_pass(mut builder: PrimaryCommandBufferBuilder) -> PrimaryCommandBufferBuilder {
+    builder
         .end_render_pass(SubpassEndInfo::default())
         .unwrap();
+    debug::end_label(builder)
+}
+
+// Sets the viewport too since every graphics pipeline takes it as dynamic state
+pub fn bind_graphics_pipeline(mut builder: PrimaryCommandBufferBuilder, pipeline: Arc<GraphicsPipeline>, viewport: Viewport) -> PrimaryCommandBufferBuilder {
+    builder
+        .bind_pipeline_graphics(pipeline)
+        .unwrap()
+        .set_viewport(0, [viewport].into_iter().collect())
+        .unwrap();
     builder
 }
 
-pub fn create_graphics_pipeline(device: Arc<Device>, shaders: &Shaders, viewport: Viewport, render_pass: Arc<RenderPass>) -> Arc<GraphicsPipeline> {
-    let (pipeline_layout, shader_stages) = create_pipeline_layout(device.clone(), shaders);
-    let stage = create_pipeline_stage_from_shader(shaders.fragment.clone().unwrap());
-    let vertex_shader = shaders.vertex.clone().unwrap();
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Opaque,
+    // Straight alpha, source color times its alpha over the destination
+    Alpha,
+    Additive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FaceCulling {
+    None,
+    Back,
+    Front,
+}
+
+// Fixed function state that differs between materials, front faces wind counter clockwise
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineState {
+    pub blend: BlendMode,
+    pub cull: FaceCulling,
+    pub depth_test: bool,
+    pub depth_write: bool,
+}
+
+impl Default for PipelineState {
+    fn default() -> Self {
+        PipelineState {
+            blend: BlendMode::Opaque,
+            cull: FaceCulling::Back,
+            depth_test: true,
+            depth_write: true,
+        }
+    }
+}
+
+impl PipelineState {
+    // Tested against opaque geometry but doesn't occlude what's drawn after it
+    pub fn transparent() -> Self {
+        PipelineState {
+            blend: BlendMode::Alpha,
+            depth_write: false,
+            ..Default::default()
+        }
+    }
+}
+
+// The viewport is dynamic so the pipeline survives resizes and can be shared by every window whose
+// render pass has the same format, set it with the viewport passed to record_render_pass
+pub fn create_graphics_pipeline(device: Arc<Device>, shaders: &Shaders, program: ProgramHandle, state: &PipelineState, render_pass: Arc<RenderPass>) -> Arc<GraphicsPipeline> {
+    create_graphics_pipeline_specialized(device, shaders, program, &SpecializationConstants::default(), state, render_pass)
+}
+
+pub fn create_graphics_pipeline_specialized(
+    device: Arc<Device>,
+    shaders: &Shaders,
+    program: ProgramHandle,
+    specialization: &SpecializationConstants,
+    state: &PipelineState,
+    render_pass: Arc<RenderPass>
+) -> Arc<GraphicsPipeline> {
+    let name = shaders.program_name(program);
+    let program = shaders.program(program);
+    if program.is_compute() {
+        panic!("Graphics pipelines need a graphics program");
+    }
+    let (pipeline_layout, shader_stages) = create_pipeline_layout(device.clone(), program, specialization);
+    let vertex_stage = program.vertex.as_ref().expect("Graphics program has no vertex stage");
     let vertex_definition = Vert::per_vertex()
-        .definition(&vertex_shader.entry_point("main").unwrap().info().input_interface)
+        .definition(&vertex_stage.module.entry_point(&vertex_stage.entry_point).unwrap().info().input_interface)
         .unwrap();
     let subpass = Subpass::from(render_pass.clone(), 0).unwrap();
+    let blend = match state.blend {
+        BlendMode::Opaque => None,
+        BlendMode::Alpha => Some(AttachmentBlend::alpha()),
+        BlendMode::Additive => Some(AttachmentBlend::additive()),
+    };
+    let cull_mode = match state.cull {
+        FaceCulling::None => CullMode::None,
+        FaceCulling::Back => CullMode::Back,
+        FaceCulling::Front => CullMode::Front,
+    };
+    let depth = DepthState {
+        write_enable: state.depth_write,
+        compare_op: if state.depth_test { CompareOp::Less } else { CompareOp::Always },
+    };
     let graphics_pipeline = GraphicsPipeline::new(
         device.clone(), 
         None, 
@@ -181,19 +335,81 @@ pub fn create_graphics_pipeline(device: Arc<Device>, shaders: &Shaders, viewport
             stages: shader_stages.into_iter().collect(),
             vertex_input_state: Some(vertex_definition),
             input_assembly_state: Some(InputAssemblyState::default()),
-            viewport_state: Some(ViewportState{
-                viewports: [viewport].into_iter().collect(),
+            viewport_state: Some(ViewportState::default()),
+            dynamic_state: [DynamicState::Viewport].into_iter().collect(),
+            rasterization_state: Some(RasterizationState {
+                cull_mode,
+                front_face: FrontFace::CounterClockwise,
                 ..Default::default()
             }),
-            rasterization_state: Some(RasterizationState::default()),
             multisample_state: Some(MultisampleState::default()),
+            depth_stencil_state: Some(DepthStencilState {
+                depth: Some(depth),
+                ..Default::default()
+            }),
             color_blend_state: Some(ColorBlendState::with_attachment_states(
                 subpass.num_color_attachments(),
-                ColorBlendAttachmentState::default(),
+                ColorBlendAttachmentState {
+                    blend,
+                    ..Default::default()
+                },
             )),
             subpass: Some(subpass.into()),
             ..GraphicsPipelineCreateInfo::layout(pipeline_layout)
         }).expect("Failed to create graphics pipeline");
+    debug::set_object_name(&*graphics_pipeline, &format!("{} graphics pipeline", name));
     graphics_pipeline
 }
 
+
+// Depth only, for programs with just a vertex stage such as the built-in shadow program
+// Depth bias is applied in the rasterizer, scaled by the depth slope of each triangle
+pub fn create_shadow_pipeline(
+    device: Arc<Device>,
+    shaders: &Shaders,
+    program: ProgramHandle,
+    render_pass: Arc<RenderPass>,
+    depth_bias_constant: f32,
+    depth_bias_slope: f32
+) -> Arc<GraphicsPipeline> {
+    let name = shaders.program_name(program);
+    let program = shaders.program(program);
+    let (pipeline_layout, shader_stages) = create_pipeline_layout(device.clone(), program, &SpecializationConstants::default());
+    let vertex_stage = program.vertex.as_ref().expect("Shadow program has no vertex stage");
+    let vertex_definition = Vert::per_vertex()
+        .definition(&vertex_stage.module.entry_point(&vertex_stage.entry_point).unwrap().info().input_interface)
+        .unwrap();
+    let subpass = Subpass::from(render_pass.clone(), 0).unwrap();
+    let graphics_pipeline = GraphicsPipeline::new(
+        device.clone(),
+        None,
+        GraphicsPipelineCreateInfo{
+            stages: shader_stages.into_iter().collect(),
+            vertex_input_state: Some(vertex_definition),
+            input_assembly_state: Some(InputAssemblyState::default()),
+            viewport_state: Some(ViewportState::default()),
+            dynamic_state: [DynamicState::Viewport].into_iter().collect(),
+            // Light space matrices aren't Y flipped, so winding is mirrored, both faces cast shadows anyway
+            rasterization_state: Some(RasterizationState {
+                cull_mode: CullMode::None,
+                depth_bias: Some(DepthBiasState {
+                    constant_factor: depth_bias_constant,
+                    clamp: 0.0,
+                    slope_factor: depth_bias_slope,
+                }),
+                ..Default::default()
+            }),
+            multisample_state: Some(MultisampleState::default()),
+            depth_stencil_state: Some(DepthStencilState {
+                depth: Some(DepthState {
+                    write_enable: true,
+                    compare_op: CompareOp::Less,
+                }),
+                ..Default::default()
+            }),
+            subpass: Some(subpass.into()),
+            ..GraphicsPipelineCreateInfo::layout(pipeline_layout)
+        }).expect("Failed to create shadow pipeline");
+    debug::set_object_name(&*graphics_pipeline, &format!("{} shadow pipeline", name));
+    graphics_pipeline
+}
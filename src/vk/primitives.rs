@@ -0,0 +1,342 @@
+use glam::Vec3;
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use crate::vk::mesh::{self, Mesh};
+use crate::vk::Vert;
+
+// Generated geometry on the CPU, triangles wind counter clockwise seen from outside
+// Every generator is centred on the origin
+#[derive(Clone, Debug, Default)]
+pub struct MeshData {
+    pub vertices: Vec<Vert>,
+    pub indices: Vec<u32>,
+}
+
+impl MeshData {
+    pub fn upload(&self, memory_allocator: Arc<StandardMemoryAllocator>, name: &str) -> Arc<Mesh> {
+        Mesh::new(memory_allocator, name, &self.vertices, &self.indices, Vec::new())
+    }
+
+    fn push_vertex(&mut self, position: Vec3, normal: Vec3, uv: [f32; 2]) -> u32 {
+        self.vertices.push(Vert {
+            position: position.to_array(),
+            normal: normal.to_array(),
+            uv,
+            tangent: [0.0; 4],
+        });
+        self.vertices.len() as u32 - 1
+    }
+
+    // Grid of (columns + 1) * (rows + 1) vertices spanning origin to origin + u + v, u x v has to point along normal
+    fn push_grid(&mut self, origin: Vec3, u: Vec3, v: Vec3, normal: Vec3, columns: u32, rows: u32) {
+        let first = self.vertices.len() as u32;
+        for row in 0..=rows {
+            for column in 0..=columns {
+                let (s, t) = (column as f32 / columns as f32, row as f32 / rows as f32);
+                self.push_vertex(origin + u * s + v * t, normal, [s, 1.0 - t]);
+            }
+        }
+        for row in 0..rows {
+            for column in 0..columns {
+                let p00 = first + row * (columns + 1) + column;
+                let (p10, p01) = (p00 + 1, p00 + columns + 1);
+                let p11 = p01 + 1;
+                self.indices.extend([p00, p10, p11, p11, p01, p00]);
+            }
+        }
+    }
+
+    // Disc at height y facing up or down
+    fn push_cap(&mut self, y: f32, radius: f32, segments: u32, up: bool) {
+        let normal = if up { Vec3::Y } else { Vec3::NEG_Y };
+        let center = self.push_vertex(Vec3::new(0.0, y, 0.0), normal, [0.5, 0.5]);
+        for segment in 0..=segments {
+            let theta = 2.0 * PI * segment as f32 / segments as f32;
+            let (sin, cos) = theta.sin_cos();
+            self.push_vertex(Vec3::new(sin * radius, y, cos * radius), normal, [0.5 + sin * 0.5, 0.5 - cos * 0.5]);
+        }
+        for segment in 0..segments {
+            let (a, b) = (center + 1 + segment, center + 2 + segment);
+            if up {
+                self.indices.extend([center, a, b]);
+            } else {
+                self.indices.extend([center, b, a]);
+            }
+        }
+    }
+
+    fn finish(mut self) -> Self {
+        mesh::compute_tangents(&mut self.vertices, &self.indices);
+        self
+    }
+}
+
+// In the XY plane facing +Z
+pub fn quad(width: f32, height: f32) -> MeshData {
+    let mut data = MeshData::default();
+    data.push_grid(Vec3::new(-width / 2.0, -height / 2.0, 0.0), Vec3::X * width, Vec3::Y * height, Vec3::Z, 1, 1);
+    data.finish()
+}
+
+// In the XZ plane facing +Y, subdivided into columns along X and rows along Z
+pub fn plane(width: f32, depth: f32, columns: u32, rows: u32) -> MeshData {
+    assert!(columns > 0 && rows > 0, "A plane needs at least one column and row");
+    let mut data = MeshData::default();
+    data.push_grid(Vec3::new(-width / 2.0, 0.0, depth / 2.0), Vec3::X * width, Vec3::NEG_Z * depth, Vec3::Y, columns, rows);
+    data.finish()
+}
+
+// Every face has its own vertices so normals and uvs stay sharp
+pub fn cube(size: f32) -> MeshData {
+    let mut data = MeshData::default();
+    let faces = [
+        (Vec3::Z, Vec3::X, Vec3::Y),
+        (Vec3::NEG_Z, Vec3::NEG_X, Vec3::Y),
+        (Vec3::X, Vec3::NEG_Z, Vec3::Y),
+        (Vec3::NEG_X, Vec3::Z, Vec3::Y),
+        (Vec3::Y, Vec3::X, Vec3::NEG_Z),
+        (Vec3::NEG_Y, Vec3::X, Vec3::Z),
+    ];
+    let half = size / 2.0;
+    for (normal, u, v) in faces {
+        data.push_grid((normal - u - v) * half, u * size, v * size, normal, 1, 1);
+    }
+    data.finish()
+}
+
+// segments around the Y axis, rings from pole to pole, the seam and poles have duplicated vertices for the uvs
+pub fn uv_sphere(radius: f32, segments: u32, rings: u32) -> MeshData {
+    assert!(segments >= 3 && rings >= 2, "A sphere needs at least 3 segments and 2 rings");
+    let mut data = MeshData::default();
+    for ring in 0..=rings {
+        let phi = PI * ring as f32 / rings as f32;
+        for segment in 0..=segments {
+            let theta = 2.0 * PI * segment as f32 / segments as f32;
+            let normal = Vec3::new(phi.sin() * theta.sin(), phi.cos(), phi.sin() * theta.cos());
+            data.push_vertex(normal * radius, normal, [segment as f32 / segments as f32, ring as f32 / rings as f32]);
+        }
+    }
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let a = ring * (segments + 1) + segment;
+            let (b, c) = (a + 1, a + segments + 1);
+            let d = c + 1;
+            // The triangles touching the poles would be degenerate
+            if ring != 0 {
+                data.indices.extend([a, c, b]);
+            }
+            if ring != rings - 1 {
+                data.indices.extend([b, c, d]);
+            }
+        }
+    }
+    data.finish()
+}
+
+// Subdivided icosahedron, more uniform than a uv sphere, mapped with the same uvs
+pub fn icosphere(radius: f32, subdivisions: u32) -> MeshData {
+    let t = (1.0 + 5f32.sqrt()) / 2.0;
+    let mut positions: Vec<Vec3> = [
+        [-1.0, t, 0.0], [1.0, t, 0.0], [-1.0, -t, 0.0], [1.0, -t, 0.0],
+        [0.0, -1.0, t], [0.0, 1.0, t], [0.0, -1.0, -t], [0.0, 1.0, -t],
+        [t, 0.0, -1.0], [t, 0.0, 1.0], [-t, 0.0, -1.0], [-t, 0.0, 1.0],
+    ].iter().map(|&position| Vec3::from(position).normalize()).collect();
+    let mut triangles: Vec<[u32; 3]> = vec![
+        [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+        [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+        [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+        [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+    ];
+    for _ in 0..subdivisions {
+        let mut midpoints = HashMap::new();
+        let mut midpoint = |a: u32, b: u32| -> u32 {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                positions.push(((positions[a as usize] + positions[b as usize]) / 2.0).normalize());
+                positions.len() as u32 - 1
+            })
+        };
+        triangles = triangles.iter().flat_map(|&[a, b, c]| {
+            let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+            [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+        }).collect();
+    }
+
+    // Vertices are shared except across the seam, where triangles that wrap around get copies with u past 1,
+    // and at the poles, where every triangle gets its own vertex with u between its other two
+    let uv = |normal: Vec3| [0.5 + normal.x.atan2(normal.z) / (2.0 * PI), normal.y.clamp(-1.0, 1.0).acos() / PI];
+    let is_pole = |normal: Vec3| normal.x.abs() < 1e-6 && normal.z.abs() < 1e-6;
+    let mut data = MeshData::default();
+    let mut shared: HashMap<(u32, bool), u32> = HashMap::new();
+    for triangle in triangles {
+        let normals = triangle.map(|i| positions[i as usize]);
+        let mut uvs = normals.map(uv);
+        let side_us: Vec<f32> = (0..3).filter(|&k| !is_pole(normals[k])).map(|k| uvs[k][0]).collect();
+        let wraps = side_us.iter().cloned().fold(f32::MIN, f32::max) - side_us.iter().cloned().fold(f32::MAX, f32::min) > 0.5;
+        let mut corners = [0; 3];
+        for k in 0..3 {
+            if is_pole(normals[k]) {
+                continue;
+            }
+            let wrapped = wraps && uvs[k][0] < 0.5;
+            if wrapped {
+                uvs[k][0] += 1.0;
+            }
+            corners[k] = *shared.entry((triangle[k], wrapped))
+                .or_insert_with(|| data.push_vertex(normals[k] * radius, normals[k], uvs[k]));
+        }
+        for k in 0..3 {
+            if is_pole(normals[k]) {
+                let u = (uvs[(k + 1) % 3][0] + uvs[(k + 2) % 3][0]) / 2.0;
+                corners[k] = data.push_vertex(normals[k] * radius, normals[k], [u, uvs[k][1]]);
+            }
+        }
+        data.indices.extend(corners);
+    }
+    data.finish()
+}
+
+// Along the Y axis, capped at both ends
+pub fn cylinder(radius: f32, height: f32, segments: u32) -> MeshData {
+    assert!(segments >= 3, "A cylinder needs at least 3 segments");
+    let mut data = MeshData::default();
+    let half = height / 2.0;
+    for segment in 0..=segments {
+        let s = segment as f32 / segments as f32;
+        let (sin, cos) = (2.0 * PI * s).sin_cos();
+        let normal = Vec3::new(sin, 0.0, cos);
+        data.push_vertex(normal * radius - Vec3::Y * half, normal, [s, 1.0]);
+        data.push_vertex(normal * radius + Vec3::Y * half, normal, [s, 0.0]);
+    }
+    for segment in 0..segments {
+        let (a, c) = (segment * 2, segment * 2 + 1);
+        let (b, d) = (a + 2, c + 2);
+        data.indices.extend([a, b, c, c, b, d]);
+    }
+    data.push_cap(half, radius, segments, true);
+    data.push_cap(-half, radius, segments, false);
+    data.finish()
+}
+
+// Along the Y axis with the tip at the top, the tip is duplicated per segment so the side shades smoothly
+pub fn cone(radius: f32, height: f32, segments: u32) -> MeshData {
+    assert!(segments >= 3, "A cone needs at least 3 segments");
+    let mut data = MeshData::default();
+    let half = height / 2.0;
+    let side_normal = |theta: f32| Vec3::new(theta.sin() * height, radius, theta.cos() * height).normalize();
+    for segment in 0..=segments {
+        let s = segment as f32 / segments as f32;
+        let theta = 2.0 * PI * s;
+        let base = Vec3::new(theta.sin() * radius, -half, theta.cos() * radius);
+        data.push_vertex(base, side_normal(theta), [s, 1.0]);
+        let mid_theta = 2.0 * PI * (segment as f32 + 0.5) / segments as f32;
+        data.push_vertex(Vec3::Y * half, side_normal(mid_theta), [s + 0.5 / segments as f32, 0.0]);
+    }
+    for segment in 0..segments {
+        let (a, tip) = (segment * 2, segment * 2 + 1);
+        data.indices.extend([a, a + 2, tip]);
+    }
+    data.push_cap(-half, radius, segments, false);
+    data.finish()
+}
+
+// Lying in the XZ plane, major_radius to the centre of the tube
+pub fn torus(major_radius: f32, minor_radius: f32, major_segments: u32, minor_segments: u32) -> MeshData {
+    assert!(major_segments >= 3 && minor_segments >= 3, "A torus needs at least 3 segments each way");
+    let mut data = MeshData::default();
+    for i in 0..=major_segments {
+        let s = i as f32 / major_segments as f32;
+        let (sin_u, cos_u) = (2.0 * PI * s).sin_cos();
+        let center = Vec3::new(sin_u, 0.0, cos_u) * major_radius;
+        for j in 0..=minor_segments {
+            let t = j as f32 / minor_segments as f32;
+            let (sin_v, cos_v) = (2.0 * PI * t).sin_cos();
+            let normal = Vec3::new(sin_u * cos_v, sin_v, cos_u * cos_v);
+            data.push_vertex(center + normal * minor_radius, normal, [s, t]);
+        }
+    }
+    for i in 0..major_segments {
+        for j in 0..minor_segments {
+            let a = i * (minor_segments + 1) + j;
+            let (b, c) = (a + minor_segments + 1, a + 1);
+            let d = b + 1;
+            data.indices.extend([a, b, c, c, b, d]);
+        }
+    }
+    data.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generated() -> Vec<(&'static str, MeshData)> {
+        vec![
+            ("quad", quad(2.0, 1.0)),
+            ("plane", plane(4.0, 3.0, 3, 5)),
+            ("cube", cube(1.5)),
+            ("uv_sphere", uv_sphere(2.0, 16, 9)),
+            ("icosphere 0", icosphere(1.0, 0)),
+            ("icosphere 3", icosphere(2.0, 3)),
+            ("cylinder", cylinder(0.5, 2.0, 12)),
+            ("cone", cone(1.0, 2.0, 10)),
+            ("torus", torus(2.0, 0.5, 24, 12)),
+        ]
+    }
+
+    #[test]
+    fn indices_are_in_range() {
+        for (name, data) in generated() {
+            assert!(!data.indices.is_empty(), "{} has no triangles", name);
+            assert_eq!(data.indices.len() % 3, 0, "{} has a partial triangle", name);
+            assert!(data.indices.iter().all(|&index| (index as usize) < data.vertices.len()), "{} indexes past its vertices", name);
+        }
+    }
+
+    #[test]
+    fn normals_have_unit_length() {
+        for (name, data) in generated() {
+            for vertex in &data.vertices {
+                let length = Vec3::from(vertex.normal).length();
+                assert!((length - 1.0).abs() < 1e-4, "{} has a normal of length {}", name, length);
+            }
+        }
+    }
+
+    #[test]
+    fn triangles_wind_counter_clockwise_around_their_normals() {
+        for (name, data) in generated() {
+            for triangle in data.indices.chunks_exact(3) {
+                let [a, b, c] = [0, 1, 2].map(|k| &data.vertices[triangle[k] as usize]);
+                let [pa, pb, pc] = [a, b, c].map(|vertex| Vec3::from(vertex.position));
+                let face_normal = (pb - pa).cross(pc - pa);
+                let vertex_normal = Vec3::from(a.normal) + Vec3::from(b.normal) + Vec3::from(c.normal);
+                assert!(face_normal.dot(vertex_normal) > 0.0, "{} has a triangle facing away from its normals", name);
+            }
+        }
+    }
+
+    #[test]
+    fn icosphere_triangles_dont_span_the_seam() {
+        let data = icosphere(1.0, 3);
+        for triangle in data.indices.chunks_exact(3) {
+            let us = [0, 1, 2].map(|k| data.vertices[triangle[k] as usize].uv[0]);
+            let span = us.iter().cloned().fold(f32::MIN, f32::max) - us.iter().cloned().fold(f32::MAX, f32::min);
+            assert!(span < 0.5, "Triangle spans {} of the texture", span);
+        }
+    }
+
+    #[test]
+    fn icosphere_tangents_follow_u() {
+        let data = icosphere(1.0, 3);
+        for vertex in data.vertices.iter().filter(|vertex| vertex.normal[1].abs() < 0.9) {
+            let normal = Vec3::from(vertex.normal);
+            let theta = normal.x.atan2(normal.z);
+            let along_u = Vec3::new(theta.cos(), 0.0, -theta.sin());
+            let tangent = Vec3::new(vertex.tangent[0], vertex.tangent[1], vertex.tangent[2]);
+            assert!(tangent.dot(along_u) > 0.9, "Tangent {:?} at {:?} doesn't follow u", tangent, normal);
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use glam::{Mat4, Quat, Vec3};
+use vulkano::buffer::BufferContents;
+use std::sync::Arc;
+
+use crate::vk::camera::{Camera, Projection};
+use crate::vk::gltf_import::GltfScene;
+use crate::vk::mesh::Mesh;
+use crate::vk::material::MaterialHandle;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    Directional,
+    Point,
+    // Cone angles in radians from the light's direction
+    Spot { inner_cone_angle: f32, outer_cone_angle: f32 },
+}
+
+// Points down the node's -Z
+#[derive(Clone, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    // None means no cutoff
+    pub range: Option<f32>,
+    // Only drawn with shadows while the shadow maps have room for it
+    pub cast_shadows: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform { translation: Vec3::ZERO, rotation: Quat::IDENTITY, scale: Vec3::ONE }
+    }
+}
+
+impl Transform {
+    pub fn from_translation(translation: Vec3) -> Self {
+        Transform { translation, ..Default::default() }
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+}
+
+// Handle to a node of a Scene, stays valid until the node is removed
+// Slots get reused, the generation tells a removed node's id apart from the one that took its slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index: usize,
+    generation: u32,
+}
+
+pub struct Node {
+    pub name: String,
+    pub transform: Transform,
+    pub mesh: Option<Arc<Mesh>>,
+    // Indexed by the mesh's submesh material slots, slots past the end use the app's default material
+    pub materials: Vec<MaterialHandle>,
+    pub light: Option<Light>,
+    pub camera: Option<Projection>,
+    pub visible: bool,
+    pub cast_shadows: bool,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    // Updated by Scene::update_world_transforms
+    world: Mat4,
+}
+
+impl Node {
+    pub fn new(name: &str) -> Self {
+        Node {
+            name: name.to_string(),
+            transform: Transform::default(),
+            mesh: None,
+            materials: Vec::new(),
+            light: None,
+            camera: None,
+            visible: true,
+            cast_shadows: true,
+            parent: None,
+            children: Vec::new(),
+            world: Mat4::IDENTITY,
+        }
+    }
+
+    pub fn with_transform(mut self, transform: Transform) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    pub fn with_mesh(mut self, mesh: Arc<Mesh>, materials: Vec<MaterialHandle>) -> Self {
+        self.mesh = Some(mesh);
+        self.materials = materials;
+        self
+    }
+
+    pub fn with_light(mut self, light: Light) -> Self {
+        self.light = Some(light);
+        self
+    }
+
+    pub fn with_camera(mut self, projection: Projection) -> Self {
+        self.camera = Some(projection);
+        self
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    // As of the last update_world_transforms
+    pub fn world_transform(&self) -> Mat4 {
+        self.world
+    }
+}
+
+// What the renderer needs to draw one submesh of a node's mesh
+pub struct DrawItem {
+    pub mesh: Arc<Mesh>,
+    pub submesh: usize,
+    // None for the app's default material
+    pub material: Option<MaterialHandle>,
+    pub world: Mat4,
+    pub cast_shadows: bool,
+}
+
+// Pushed for every draw to programs that declare a push constant block, i.e.
+// `layout(push_constant) uniform Model { mat4 model; }`
+#[derive(BufferContents, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ModelPushConstants {
+    pub model: [[f32; 4]; 4],
+}
+
+// Bumped every time its node is removed
+#[derive(Default)]
+struct Slot {
+    generation: u32,
+    node: Option<Node>,
+}
+
+// Nodes live in slots that get reused once removed
+#[derive(Default)]
+pub struct Scene {
+    nodes: Vec<Slot>,
+    free: Vec<usize>,
+    roots: Vec<NodeId>,
+    // The node whose camera component drives the app camera
+    pub active_camera: Option<NodeId>,
+    // Linear color lighting every surface evenly, on top of the light components
+    pub ambient: [f32; 3],
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Scene::default()
+    }
+
+    pub fn add(&mut self, node: Node, parent: Option<NodeId>) -> NodeId {
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.nodes[index];
+                slot.node = Some(node);
+                NodeId { index, generation: slot.generation }
+            }
+            None => {
+                self.nodes.push(Slot { generation: 0, node: Some(node) });
+                NodeId { index: self.nodes.len() - 1, generation: 0 }
+            }
+        };
+        self.attach(id, parent);
+        id
+    }
+
+    // Removes the node and everything below it
+    pub fn remove(&mut self, id: NodeId) {
+        self.detach(id);
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let slot = &mut self.nodes[id.index];
+            let node = slot.node.take().expect("Node was already removed");
+            slot.generation = slot.generation.wrapping_add(1);
+            stack.extend(node.children);
+            self.free.push(id.index);
+            if self.active_camera == Some(id) {
+                self.active_camera = None;
+            }
+        }
+    }
+
+    // Keeps the local transform, so the node moves with its new parent
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) {
+        let mut ancestor = parent;
+        while let Some(ancestor_id) = ancestor {
+            if ancestor_id == id {
+                panic!("Node `{}` can't be parented to its own descendant", self.node(id).name);
+            }
+            ancestor = self.node(ancestor_id).parent;
+        }
+        self.detach(id);
+        self.attach(id, parent);
+    }
+
+    fn attach(&mut self, id: NodeId, parent: Option<NodeId>) {
+        self.node_mut(id).parent = parent;
+        match parent {
+            Some(parent) => self.node_mut(parent).children.push(id),
+            None => self.roots.push(id),
+        }
+    }
+
+    fn detach(&mut self, id: NodeId) {
+        match self.node(id).parent {
+            Some(parent) => self.node_mut(parent).children.retain(|&child| child != id),
+            None => self.roots.retain(|&root| root != id),
+        }
+    }
+
+    // Panics if the node was removed, use get for ids that may be stale
+    pub fn node(&self, id: NodeId) -> &Node {
+        self.get(id).expect("Node was removed")
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        self.get_mut(id).expect("Node was removed")
+    }
+
+    // None once the node was removed, even if another node took its slot
+    pub fn get(&self, id: NodeId) -> Option<&Node> {
+        self.nodes.get(id.index).filter(|slot| slot.generation == id.generation).and_then(|slot| slot.node.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        self.nodes.get_mut(id.index).filter(|slot| slot.generation == id.generation).and_then(|slot| slot.node.as_mut())
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.get(id).is_some()
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.iter().find(|(_, node)| node.name == name).map(|(id, _)| id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.nodes.iter().enumerate().filter_map(|(index, slot)| {
+            slot.node.as_ref().map(|node| (NodeId { index, generation: slot.generation }, node))
+        })
+    }
+
+    // Propagates local transforms down from the roots, parents before children
+    pub fn update_world_transforms(&mut self) {
+        let mut stack: Vec<(NodeId, Mat4)> = self.roots.iter().map(|&root| (root, Mat4::IDENTITY)).collect();
+        while let Some((id, parent_world)) = stack.pop() {
+            let node = self.node_mut(id);
+            node.world = parent_world * node.transform.matrix();
+            let world = node.world;
+            stack.extend(node.children.iter().map(|&child| (child, world)));
+        }
+    }
+
+    // Submeshes of visible nodes, hiding a node hides everything below it
+    pub fn draw_items(&self) -> Vec<DrawItem> {
+        self.collect_draw_items(self.roots.clone())
+    }
+
+    // Like draw_items for only the node and its descendants, empty once the node is removed
+    pub fn subtree_draw_items(&self, root: NodeId) -> Vec<DrawItem> {
+        if !self.contains(root) {
+            return Vec::new();
+        }
+        self.collect_draw_items(vec![root])
+    }
+
+    fn collect_draw_items(&self, mut stack: Vec<NodeId>) -> Vec<DrawItem> {
+        let mut items = Vec::new();
+        while let Some(id) = stack.pop() {
+            let node = self.node(id);
+            if !node.visible {
+                continue;
+            }
+            if let Some(mesh) = &node.mesh {
+                for (i, submesh) in mesh.submeshes.iter().enumerate() {
+                    items.push(DrawItem {
+                        mesh: mesh.clone(),
+                        submesh: i,
+                        material: node.materials.get(submesh.material_slot).copied(),
+                        world: node.world,
+                        cast_shadows: node.cast_shadows,
+                    });
+                }
+            }
+            stack.extend(node.children.iter().copied());
+        }
+        items
+    }
+
+    pub fn lights(&self) -> impl Iterator<Item = (&Light, Mat4)> {
+        self.iter().filter_map(|(_, node)| node.light.as_ref().map(|light| (light, node.world)))
+    }
+
+    // Moves the camera to the active camera node, scale is ignored
+    pub fn apply_active_camera(&self, camera: &mut Camera) {
+        let node = match self.active_camera {
+            Some(id) => self.node(id),
+            None => return,
+        };
+        let (_, rotation, translation) = node.world.to_scale_rotation_translation();
+        camera.position = translation;
+        camera.rotation = rotation;
+        if let Some(projection) = node.camera {
+            camera.projection = projection;
+        }
+    }
+
+    // Adds the default scene of an imported glTF file under parent, returns the nodes created for its roots
+    // materials has one handle per GltfScene::materials entry, empty draws everything with the default material
+    pub fn add_gltf(&mut self, gltf: &GltfScene, parent: Option<NodeId>, materials: &[MaterialHandle]) -> Vec<NodeId> {
+        let mut created = Vec::new();
+        // Reversed so nodes are added in file order
+        let mut stack: Vec<(usize, Option<NodeId>, bool)> = gltf.roots.iter().rev().map(|&root| (root, parent, true)).collect();
+        while let Some((i, parent, is_root)) = stack.pop() {
+            let gltf_node = &gltf.nodes[i];
+            let mut node = Node::new(gltf_node.name.as_deref().unwrap_or("node")).with_transform(Transform {
+                translation: gltf_node.translation,
+                rotation: gltf_node.rotation,
+                scale: gltf_node.scale,
+            });
+            if let Some(mesh) = gltf_node.mesh {
+                node = node.with_mesh(gltf.meshes[mesh].mesh.clone(), materials.to_vec());
+            }
+            if let Some(light) = gltf_node.light {
+                let light = &gltf.lights[light];
+                node = node.with_light(Light {
+                    kind: light.kind,
+                    color: light.color,
+                    intensity: light.intensity,
+                    range: light.range,
+                    cast_shadows: true,
+                });
+            }
+            if let Some(camera) = gltf_node.camera {
+                node = node.with_camera(gltf.cameras[camera].projection);
+            }
+            let id = self.add(node, parent);
+            if is_root {
+                created.push(id);
+            }
+            stack.extend(gltf_node.children.iter().rev().map(|&child| (child, Some(id), false)));
+        }
+        created
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removed_ids_stay_invalid_after_their_slot_is_reused() {
+        let mut scene = Scene::new();
+        let removed = scene.add(Node::new("removed"), None);
+        scene.remove(removed);
+        let reused = scene.add(Node::new("reused"), None);
+        assert!(scene.get(removed).is_none());
+        assert!(scene.get_mut(removed).is_none());
+        assert_eq!(scene.node(reused).name, "reused");
+        assert_eq!(scene.find("reused"), Some(reused));
+    }
+
+    #[test]
+    fn removing_a_node_removes_its_descendants() {
+        let mut scene = Scene::new();
+        let parent = scene.add(Node::new("parent"), None);
+        let child = scene.add(Node::new("child"), Some(parent));
+        let grandchild = scene.add(Node::new("grandchild"), Some(child));
+        scene.remove(parent);
+        assert!(!scene.contains(child));
+        assert!(!scene.contains(grandchild));
+        assert!(scene.roots().is_empty());
+        assert!(scene.subtree_draw_items(child).is_empty());
+    }
+}
@@ -1,91 +1,324 @@
 use vulkano::device::Device;
-use vulkano::shader::{ShaderModule, ShaderModuleCreateInfo };
+use vulkano::shader::{ShaderModule, ShaderModuleCreateInfo, SpecializationConstant};
+use vulkano::shader::spirv::{Spirv, Instruction, Decoration, Id, StorageClass};
 use std::sync::Arc;
-use shaderc::{Compiler, CompileOptions, ShaderKind};
+use shaderc::{Compiler, CompileOptions, ShaderKind, SourceLanguage};
 use std::path::Path;
 use std::fs::File;
 use std::io::Read;
+use std::collections::HashMap;
+use crate::vk::debug;
 
-pub struct Shaders {
-    pub vertex: Option<Arc<ShaderModule>>,
-    pub fragment: Option<Arc<ShaderModule>>,
-    pub compute: Option<Arc<ShaderModule>>,
-    compiler: Option<Compiler>,
-    compiler_options: Option<Box<CompileOptions<'static>>>,
-    device: Arc<Device>,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+    Compute,
 }
 
-impl Shaders {
-    pub fn new(device: Arc<Device>) -> Self {
-        Self { vertex: None, fragment: None, compute: None, compiler: None, compiler_options: None, device }
+impl ShaderStage {
+    // Infers the stage from the file extension (.vert/.vs, .frag/.fs, .comp)
+    // For HLSL files the stage is taken from the inner extension, i.e. `lit.vs.hlsl`
+    pub fn from_path(path: impl AsRef<Path>) -> Option<ShaderStage> {
+        let mut path = path.as_ref();
+        if path.extension()? == "hlsl" {
+            path = Path::new(path.file_stem()?);
+        }
+        match path.extension()?.to_str()? {
+            "vert" | "vs" => Some(ShaderStage::Vertex),
+            "frag" | "fs" => Some(ShaderStage::Fragment),
+            "comp" => Some(ShaderStage::Compute),
+            _ => None,
+        }
     }
 
-    pub fn load_shader_from_file(&mut self, path: impl AsRef<Path>, shader_type: &str) {
-        let mut kind = ShaderKind::Compute;
-        let mut shader_module: Option<Arc<ShaderModule>> = None;
-        match shader_type {
-            "vertex" => kind = ShaderKind::Vertex,
-            "fragment" => kind = ShaderKind::Fragment,
-            "compute" => kind = ShaderKind::Compute,
-            _ => panic!("Invalid shader type"),
+    fn kind(self) -> ShaderKind {
+        match self {
+            ShaderStage::Vertex => ShaderKind::Vertex,
+            ShaderStage::Fragment => ShaderKind::Fragment,
+            ShaderStage::Compute => ShaderKind::Compute,
         }
-        let spirv = self.compile_shader_from_file(path, kind);
-        unsafe {
-            shader_module = Some(ShaderModule::new(self.device.clone(), ShaderModuleCreateInfo::new(&spirv)).unwrap());
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShaderLanguage {
+    Glsl,
+    Hlsl,
+}
+
+impl ShaderLanguage {
+    // Anything that isn't a .hlsl file is treated as GLSL
+    pub fn from_path(path: impl AsRef<Path>) -> ShaderLanguage {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("hlsl") => ShaderLanguage::Hlsl,
+            _ => ShaderLanguage::Glsl,
         }
-        match shader_type {
-            "vertex" => self.vertex = shader_module,
-            "fragment" => self.fragment = shader_module,
-            "compute" => self.compute = shader_module,
-            _ => panic!("Invalid shader type"),
+    }
+}
+
+// Maps an HLSL register (i.e. "t0", "b1", "u2") to a Vulkan descriptor set and binding
+#[derive(Clone, Debug)]
+pub struct HlslRegisterBinding {
+    pub register: String,
+    pub set: u32,
+    pub binding: u32,
+}
+
+// A single stage of a program: the module and the entry point to run in it
+#[derive(Clone)]
+pub struct ProgramStage {
+    pub stage: ShaderStage,
+    pub module: Arc<ShaderModule>,
+    pub entry_point: String,
+    // Reflected specialization constant names -> constant ids
+    pub specialization_ids: HashMap<String, u32>,
+    // Reflected (set, binding) -> size in bytes of the uniform blocks the stage declares
+    pub uniform_block_sizes: HashMap<(u32, u32), u32>,
+}
+
+impl ProgramStage {
+    pub fn new(stage: ShaderStage, module: Arc<ShaderModule>) -> Self {
+        Self::with_entry_point(stage, module, "main")
+    }
+
+    pub fn with_entry_point(stage: ShaderStage, module: Arc<ShaderModule>, entry_point: &str) -> Self {
+        Self { stage, module, entry_point: entry_point.to_string(), specialization_ids: HashMap::new(), uniform_block_sizes: HashMap::new() }
+    }
+}
+
+// Specialization constant values applied when a pipeline is created
+// Values can be given by constant_id or by the constant's name in the shader source
+#[derive(Clone, Default)]
+pub struct SpecializationConstants {
+    by_id: HashMap<u32, SpecializationConstant>,
+    by_name: HashMap<String, SpecializationConstant>,
+}
+
+impl SpecializationConstants {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_id(mut self, id: u32, value: SpecializationConstant) -> Self {
+        self.by_id.insert(id, value);
+        self
+    }
+
+    pub fn set_name(mut self, name: &str, value: SpecializationConstant) -> Self {
+        self.by_name.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty() && self.by_name.is_empty()
+    }
+
+    // Resolves the values that apply to a stage, named values win over ids when both are given
+    pub fn for_stage(&self, stage: &ProgramStage) -> HashMap<u32, SpecializationConstant> {
+        let declared = stage.module.specialization_constants();
+        let mut values: HashMap<u32, SpecializationConstant> = self.by_id.iter()
+            .filter(|(id, _)| declared.contains_key(id))
+            .map(|(id, value)| (*id, *value))
+            .collect();
+        for (name, value) in &self.by_name {
+            if let Some(id) = stage.specialization_ids.get(name) {
+                values.insert(*id, *value);
+            }
         }
+        values
     }
 
-    pub fn load_shader_from_string(&mut self, source: &str, shader_type: &str) {
-        let mut kind = ShaderKind::Compute;
-        let mut shader_module: Option<Arc<ShaderModule>> = None;
-        match shader_type {
-            "vertex" => kind = ShaderKind::Vertex,
-            "fragment" => kind = ShaderKind::Fragment,
-            "compute" => kind = ShaderKind::Compute,
-            _ => panic!("Invalid shader type"),
+    // Panics on names that no stage of the program declares, these are almost always typos
+    pub fn check_names(&self, program: &ShaderProgram) {
+        for name in self.by_name.keys() {
+            if !program.stages().any(|stage| stage.specialization_ids.contains_key(name)) {
+                panic!("Specialization constant `{}` not found in program", name);
+            }
         }
-        let spirv = self.compile_shader_from_string(source, kind);
-        unsafe {
-            shader_module = Some(ShaderModule::new(self.device.clone(), ShaderModuleCreateInfo::new(&spirv)).unwrap());
+    }
+}
+
+// The set of stages a pipeline is built from
+// Graphics programs use vertex + fragment, compute programs only use compute
+#[derive(Clone, Default)]
+pub struct ShaderProgram {
+    pub vertex: Option<ProgramStage>,
+    pub fragment: Option<ProgramStage>,
+    pub compute: Option<ProgramStage>,
+}
+
+impl ShaderProgram {
+    pub fn graphics(vertex: ProgramStage, fragment: ProgramStage) -> Self {
+        Self { vertex: Some(vertex), fragment: Some(fragment), compute: None }
+    }
+
+    pub fn compute(compute: ProgramStage) -> Self {
+        Self { vertex: None, fragment: None, compute: Some(compute) }
+    }
+
+    // Slots each stage by its ShaderStage, later stages of the same kind replace earlier ones
+    pub fn from_stages(stages: impl IntoIterator<Item = ProgramStage>) -> Self {
+        let mut program = ShaderProgram::default();
+        for stage in stages {
+            match stage.stage {
+                ShaderStage::Vertex => program.vertex = Some(stage),
+                ShaderStage::Fragment => program.fragment = Some(stage),
+                ShaderStage::Compute => program.compute = Some(stage),
+            }
         }
-        match shader_type {
-            "vertex" => self.vertex = shader_module,
-            "fragment" => self.fragment = shader_module,
-            "compute" => self.compute = shader_module,
-            _ => panic!("Invalid shader type"),
+        program
+    }
+
+    pub fn is_compute(&self) -> bool {
+        self.compute.is_some()
+    }
+
+    pub fn stages(&self) -> impl Iterator<Item = &ProgramStage> {
+        [&self.vertex, &self.fragment, &self.compute].into_iter().flatten()
+    }
+}
+
+// Handle to a program registered in a Shaders library
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProgramHandle(pub(crate) usize);
+
+// Shader library, holds the compiler and every named program registered with it
+pub struct Shaders {
+    programs: Vec<(String, ShaderProgram)>,
+    program_names: HashMap<String, ProgramHandle>,
+    compiler: Option<Compiler>,
+    compiler_options: Option<Box<CompileOptions<'static>>>,
+    hlsl_compiler_options: Option<Box<CompileOptions<'static>>>,
+    hlsl_register_bindings: Vec<HlslRegisterBinding>,
+    device: Arc<Device>,
+}
+
+impl Shaders {
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            programs: Vec::new(),
+            program_names: HashMap::new(),
+            compiler: None,
+            compiler_options: None,
+            hlsl_compiler_options: None,
+            hlsl_register_bindings: Vec::new(),
+            device,
         }
     }
 
-    fn compile_shader_from_file(&mut self, path: impl AsRef<Path>, kind: ShaderKind) -> Vec<u32> {
-        if self.compiler.is_none() {
-            self.compiler = Some(Compiler::new().unwrap());
+    // Registers not mapped explicitly are bound automatically by shaderc
+    pub fn map_hlsl_register(&mut self, register: &str, set: u32, binding: u32) {
+        self.hlsl_register_bindings.retain(|mapping| mapping.register != register);
+        self.hlsl_register_bindings.push(HlslRegisterBinding { register: register.to_string(), set, binding });
+        // Options get rebuilt with the new mapping on the next HLSL compile
+        self.hlsl_compiler_options = None;
+    }
+
+    // Registers a program under the given name, replacing any program already registered with it
+    pub fn register_program(&mut self, name: &str, program: ShaderProgram) -> ProgramHandle {
+        if program.is_compute() && (program.vertex.is_some() || program.fragment.is_some()) {
+            panic!("Program `{}` mixes compute and graphics stages", name);
         }
-        let compiler = self.compiler.as_ref().unwrap();
-        if self.compiler_options.is_none() {
-            self.compiler_options = Some(Box::new(CompileOptions::new().unwrap()));
+        if let Some(handle) = self.program_names.get(name) {
+            self.programs[handle.0].1 = program;
+            return *handle;
         }
-        let options = self.compiler_options.as_ref().unwrap();
+        let handle = ProgramHandle(self.programs.len());
+        self.programs.push((name.to_string(), program));
+        self.program_names.insert(name.to_string(), handle);
+        handle
+    }
+
+    pub fn program(&self, handle: ProgramHandle) -> &ShaderProgram {
+        &self.programs[handle.0].1
+    }
+
+    pub fn program_name(&self, handle: ProgramHandle) -> &str {
+        &self.programs[handle.0].0
+    }
+
+    // Size of the uniform block the program declares at set and binding, None if none of its stages reflected one
+    pub fn uniform_block_size(&self, handle: ProgramHandle, set: u32, binding: u32) -> Option<u32> {
+        self.program(handle).stages().find_map(|stage| stage.uniform_block_sizes.get(&(set, binding)).copied())
+    }
+
+    pub fn find_program(&self, name: &str) -> Option<ProgramHandle> {
+        self.program_names.get(name).copied()
+    }
+
+    // Stages are inferred from the file extensions
+    pub fn load_program_from_files(&mut self, name: &str, vertex_path: impl AsRef<Path>, fragment_path: impl AsRef<Path>) -> ProgramHandle {
+        let vertex = self.load_shader_from_file_inferred(vertex_path);
+        let fragment = self.load_shader_from_file_inferred(fragment_path);
+        self.register_program(name, ShaderProgram::graphics(vertex, fragment))
+    }
+
+    pub fn load_compute_program_from_file(&mut self, name: &str, path: impl AsRef<Path>, entry_point: &str) -> ProgramHandle {
+        let compute = self.load_shader_from_file(path, ShaderStage::Compute, entry_point);
+        self.register_program(name, ShaderProgram::compute(compute))
+    }
+
+    pub fn load_shader_from_file_inferred(&mut self, path: impl AsRef<Path>) -> ProgramStage {
+        let stage = ShaderStage::from_path(path.as_ref())
+            .unwrap_or_else(|| panic!("Can't infer shader stage from `{}`", path.as_ref().display()));
+        self.load_shader_from_file(path, stage, "main")
+    }
+
+    // The source language is inferred from the file extension
+    pub fn load_shader_from_file(&mut self, path: impl AsRef<Path>, stage: ShaderStage, entry_point: &str) -> ProgramStage {
+        let language = ShaderLanguage::from_path(path.as_ref());
+        self.load_shader_from_file_as(path, language, stage, entry_point)
+    }
+
+    pub fn load_shader_from_file_as(&mut self, path: impl AsRef<Path>, language: ShaderLanguage, stage: ShaderStage, entry_point: &str) -> ProgramStage {
+        let spirv = self.compile_shader_from_file(path.as_ref(), stage.kind(), language, entry_point);
+        self.create_program_stage(&spirv, stage, entry_point, &path.as_ref().display().to_string())
+    }
+
+    pub fn load_shader_from_string(&mut self, source: &str, language: ShaderLanguage, stage: ShaderStage, entry_point: &str) -> ProgramStage {
+        let spirv = self.compile_shader_from_string(source, stage.kind(), language, "STRING_SOURCE", entry_point);
+        self.create_program_stage(&spirv, stage, entry_point, "STRING_SOURCE")
+    }
+
+    // The module is named after its source and entry point, i.e. `shaders/vert.vs:main`
+    fn create_program_stage(&self, spirv: &[u32], stage: ShaderStage, entry_point: &str, source_name: &str) -> ProgramStage {
+        let module = unsafe {
+            ShaderModule::new(self.device.clone(), ShaderModuleCreateInfo::new(spirv)).unwrap()
+        };
+        debug::set_object_name(&*module, &format!("{}:{}", source_name, entry_point));
+        let mut program_stage = ProgramStage::with_entry_point(stage, module, entry_point);
+        program_stage.specialization_ids = reflect_specialization_ids(spirv);
+        program_stage.uniform_block_sizes = reflect_uniform_block_sizes(spirv);
+        program_stage
+    }
+
+    fn compile_shader_from_file(&mut self, path: impl AsRef<Path>, kind: ShaderKind, language: ShaderLanguage, entry_point: &str) -> Vec<u32> {
         let source = std::fs::read_to_string(path.as_ref()).unwrap();
-        let compiled = compiler.compile_into_spirv(&source, kind, path.as_ref().to_str().unwrap(), "main", Some(options)).unwrap();
-        compiled.as_binary().to_vec()
+        self.compile_shader_from_string(&source, kind, language, path.as_ref().to_str().unwrap(), entry_point)
     }
 
-    fn compile_shader_from_string(&mut self, source: &str, kind: ShaderKind) -> Vec<u32> {
+    fn compile_shader_from_string(&mut self, source: &str, kind: ShaderKind, language: ShaderLanguage, source_name: &str, entry_point: &str) -> Vec<u32> {
         if self.compiler.is_none() {
             self.compiler = Some(Compiler::new().unwrap());
         }
         let compiler = self.compiler.as_ref().unwrap();
-        if self.compiler_options.is_none() {
-            self.compiler_options = Some(Box::new(CompileOptions::new().unwrap()));
-        }
-        let options = self.compiler_options.as_ref().unwrap();
-        let compiled = compiler.compile_into_spirv(&source, kind, "STRING_SOURCE", "main", Some(options)).unwrap();
+        let options = match language {
+            ShaderLanguage::Glsl => {
+                if self.compiler_options.is_none() {
+                    self.compiler_options = Some(Box::new(CompileOptions::new().unwrap()));
+                }
+                self.compiler_options.as_ref().unwrap()
+            }
+            ShaderLanguage::Hlsl => {
+                if self.hlsl_compiler_options.is_none() {
+                    self.hlsl_compiler_options = Some(Box::new(create_hlsl_compile_options(&self.hlsl_register_bindings)));
+                }
+                self.hlsl_compiler_options.as_ref().unwrap()
+            }
+        };
+        let compiled = compiler.compile_into_spirv(source, kind, source_name, entry_point, Some(options))
+            .unwrap_or_else(|err| panic!("Failed to compile `{}`: {}", source_name, err));
         compiled.as_binary().to_vec()
     }
 
@@ -107,4 +340,112 @@ impl Shaders {
             .unwrap_or_else(|err| panic!("file `{}`: {}", path.display(), err))
             .into_owned()
     }
-}
\ No newline at end of file
+}
+
+fn create_hlsl_compile_options(register_bindings: &[HlslRegisterBinding]) -> CompileOptions<'static> {
+    let mut options = CompileOptions::new().unwrap();
+    options.set_source_language(SourceLanguage::HLSL);
+    // Use register(...) annotations for bindings and let shaderc place anything unannotated
+    options.set_hlsl_io_mapping(true);
+    options.set_hlsl_offsets(true);
+    options.set_auto_bind_uniforms(true);
+    options.set_auto_map_locations(true);
+    for mapping in register_bindings {
+        options.set_hlsl_register_set_and_binding(&mapping.register, &mapping.set.to_string(), &mapping.binding.to_string());
+    }
+    options
+}
+
+// Maps the debug names of specialization constants to their constant_id
+// Constants only show up here if the SPIR-V still has its OpName instructions
+fn reflect_specialization_ids(words: &[u32]) -> HashMap<String, u32> {
+    let spirv = Spirv::new(words).expect("Failed to parse SPIR-V");
+    spirv.iter_decoration()
+        .filter_map(|instruction| match *instruction {
+            Instruction::Decorate { target, decoration: Decoration::SpecId { specialization_constant_id } } => {
+                spirv.id(target).iter_name().find_map(|name| match name {
+                    Instruction::Name { name, .. } => Some((name.clone(), specialization_constant_id)),
+                    _ => None,
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Uniform blocks end where their last member does, members are placed by their Offset decorations
+fn reflect_uniform_block_sizes(words: &[u32]) -> HashMap<(u32, u32), u32> {
+    let spirv = Spirv::new(words).expect("Failed to parse SPIR-V");
+    spirv.iter_global()
+        .filter_map(|instruction| match *instruction {
+            Instruction::Variable { result_type_id, result_id, storage_class: StorageClass::Uniform, .. } => {
+                let (mut set, mut binding) = (None, None);
+                for decoration in spirv.id(result_id).iter_decoration() {
+                    match *decoration {
+                        Instruction::Decorate { decoration: Decoration::DescriptorSet { descriptor_set }, .. } => set = Some(descriptor_set),
+                        Instruction::Decorate { decoration: Decoration::Binding { binding_point }, .. } => binding = Some(binding_point),
+                        _ => (),
+                    }
+                }
+                let block_type = match *spirv.id(result_type_id).instruction() {
+                    Instruction::TypePointer { ty, .. } => ty,
+                    _ => return None,
+                };
+                Some(((set?, binding?), reflect_type_size(&spirv, block_type)?))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// None for types without a fixed size, like runtime arrays
+fn reflect_type_size(spirv: &Spirv, ty: Id) -> Option<u32> {
+    let info = spirv.id(ty);
+    match *info.instruction() {
+        Instruction::TypeInt { width, .. } | Instruction::TypeFloat { width, .. } => Some(width / 8),
+        Instruction::TypeBool { .. } => Some(4),
+        Instruction::TypeVector { component_type, component_count, .. } => Some(reflect_type_size(spirv, component_type)? * component_count),
+        Instruction::TypeArray { length, .. } => {
+            let stride = info.iter_decoration().find_map(|decoration| match *decoration {
+                Instruction::Decorate { decoration: Decoration::ArrayStride { array_stride }, .. } => Some(array_stride),
+                _ => None,
+            })?;
+            match spirv.id(length).instruction() {
+                Instruction::Constant { value, .. } => Some(stride * value[0]),
+                _ => None,
+            }
+        }
+        Instruction::TypeStruct { ref member_types, .. } => {
+            let mut end = 0;
+            for (&member_type, member) in member_types.iter().zip(info.iter_members()) {
+                let (mut offset, mut matrix_stride, mut row_major) = (None, None, false);
+                for decoration in member.iter_decoration() {
+                    match *decoration {
+                        Instruction::MemberDecorate { decoration: Decoration::Offset { byte_offset }, .. } => offset = Some(byte_offset),
+                        Instruction::MemberDecorate { decoration: Decoration::MatrixStride { matrix_stride: stride }, .. } => matrix_stride = Some(stride),
+                        Instruction::MemberDecorate { decoration: Decoration::RowMajor, .. } => row_major = true,
+                        _ => (),
+                    }
+                }
+                // Matrices are laid out as columns, or rows when row major, matrix_stride apart
+                let size = match *spirv.id(member_type).instruction() {
+                    Instruction::TypeMatrix { column_type, column_count, .. } => {
+                        let vectors = if row_major {
+                            match *spirv.id(column_type).instruction() {
+                                Instruction::TypeVector { component_count, .. } => component_count,
+                                _ => return None,
+                            }
+                        } else {
+                            column_count
+                        };
+                        matrix_stride? * vectors
+                    }
+                    _ => reflect_type_size(spirv, member_type)?,
+                };
+                end = end.max(offset? + size);
+            }
+            Some(end)
+        }
+        _ => None,
+    }
+}
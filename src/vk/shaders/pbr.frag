@@ -0,0 +1,220 @@
+#version 450
+
+const float PI = 3.14159265359;
+const uint DIRECTIONAL = 0u;
+const uint POINT = 1u;
+const uint SPOT = 2u;
+
+layout(location = 0) in vec3 world_position;
+layout(location = 1) in vec3 world_normal;
+layout(location = 2) in vec2 frag_uv;
+layout(location = 3) in vec4 world_tangent;
+
+layout(location = 0) out vec4 out_color;
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 camera_position;
+};
+
+// Matches pbr::GpuLight
+struct Light {
+    vec3 position;
+    // 0 means no cutoff
+    float range;
+    vec3 direction;
+    uint kind;
+    vec3 color;
+    float intensity;
+    float inner_cone_cos;
+    float outer_cone_cos;
+    // First layer in shadow_map, -1 for unshadowed lights
+    int shadow_layer;
+    uint shadow_layer_count;
+};
+
+layout(std430, set = 0, binding = 1) readonly buffer Lights {
+    vec3 ambient;
+    uint light_count;
+    Light lights[];
+};
+
+// Matches shadow::GpuShadowLayer
+struct ShadowLayer {
+    mat4 view_projection;
+    // View space distance where a directional light's cascade ends
+    float split_depth;
+};
+
+layout(std430, set = 0, binding = 2) readonly buffer Shadows {
+    // World units, grows at grazing angles
+    float normal_offset;
+    int pcf_radius;
+    float texel_size;
+    uint shadow_layer_count;
+    ShadowLayer shadow_layers[];
+};
+
+layout(set = 0, binding = 3) uniform sampler2DArrayShadow shadow_map;
+
+// Declared in the order PbrParams::to_material sets them
+layout(set = 1, binding = 0) uniform Material {
+    vec4 base_color_factor;
+    vec3 emissive_factor;
+    float metallic_factor;
+    float roughness_factor;
+    float normal_scale;
+    float occlusion_strength;
+    // Negative unless the alpha mode is Mask
+    float alpha_cutoff;
+};
+
+layout(set = 1, binding = 1) uniform sampler2D base_color_texture;
+// Metalness in blue, roughness in green
+layout(set = 1, binding = 2) uniform sampler2D metallic_roughness_texture;
+layout(set = 1, binding = 3) uniform sampler2D normal_texture;
+layout(set = 1, binding = 4) uniform sampler2D occlusion_texture;
+layout(set = 1, binding = 5) uniform sampler2D emissive_texture;
+
+float distribution_ggx(float n_dot_h, float roughness) {
+    float a = roughness * roughness;
+    float a2 = a * a;
+    float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+float geometry_smith(float n_dot_v, float n_dot_l, float roughness) {
+    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+}
+
+vec3 fresnel_schlick(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// KHR_lights_punctual falloff, inverse square with a smooth cutoff at the range
+float attenuation(Light light, vec3 to_light) {
+    if (light.kind == DIRECTIONAL) {
+        return 1.0;
+    }
+    float distance2 = max(dot(to_light, to_light), 0.0001);
+    float falloff = 1.0 / distance2;
+    if (light.range > 0.0) {
+        float ratio2 = distance2 / (light.range * light.range);
+        falloff *= pow(clamp(1.0 - ratio2 * ratio2, 0.0, 1.0), 2.0);
+    }
+    if (light.kind == SPOT) {
+        float cos_angle = dot(normalize(-to_light), light.direction);
+        falloff *= smoothstep(light.outer_cone_cos, light.inner_cone_cos, cos_angle);
+    }
+    return falloff;
+}
+
+// Directional lights pick a cascade by view depth, point lights the cube face the surface is on
+int shadow_layer_for(Light light) {
+    if (light.kind == DIRECTIONAL) {
+        float view_depth = -(view * vec4(world_position, 1.0)).z;
+        for (uint i = 0u; i < light.shadow_layer_count; i++) {
+            if (view_depth <= shadow_layers[light.shadow_layer + int(i)].split_depth) {
+                return light.shadow_layer + int(i);
+            }
+        }
+        return -1;
+    }
+    if (light.kind == POINT) {
+        // Faces in +X, -X, +Y, -Y, +Z, -Z order
+        vec3 d = world_position - light.position;
+        vec3 a = abs(d);
+        int face = a.x >= a.y && a.x >= a.z ? (d.x >= 0.0 ? 0 : 1) : a.y >= a.z ? (d.y >= 0.0 ? 2 : 3) : (d.z >= 0.0 ? 4 : 5);
+        return light.shadow_layer + face;
+    }
+    return light.shadow_layer;
+}
+
+// 1 when fully lit, filtered over (2 * pcf_radius + 1)^2 compared taps
+float shadow_factor(Light light, vec3 n, vec3 l) {
+    if (light.shadow_layer < 0) {
+        return 1.0;
+    }
+    int layer = shadow_layer_for(light);
+    if (layer < 0) {
+        return 1.0;
+    }
+    // Pushing the lookup off the surface hides acne that depth bias alone leaves at grazing angles
+    float n_dot_l = clamp(dot(n, l), 0.0, 1.0);
+    vec3 offset_position = world_position + n * normal_offset * (1.0 - n_dot_l + 0.1);
+    vec4 clip = shadow_layers[layer].view_projection * vec4(offset_position, 1.0);
+    vec3 ndc = clip.xyz / clip.w;
+    if (ndc.z <= 0.0 || ndc.z >= 1.0) {
+        return 1.0;
+    }
+    vec2 uv = ndc.xy * 0.5 + 0.5;
+    float lit = 0.0;
+    for (int y = -pcf_radius; y <= pcf_radius; y++) {
+        for (int x = -pcf_radius; x <= pcf_radius; x++) {
+            lit += texture(shadow_map, vec4(uv + vec2(x, y) * texel_size, float(layer), ndc.z));
+        }
+    }
+    float taps = float((2 * pcf_radius + 1) * (2 * pcf_radius + 1));
+    return lit / taps;
+}
+
+vec3 surface_normal() {
+    vec3 n = normalize(world_normal);
+    // Double sided materials light their back faces as seen from behind
+    if (!gl_FrontFacing) {
+        n = -n;
+    }
+    vec3 t = world_tangent.xyz - n * dot(n, world_tangent.xyz);
+    if (dot(t, t) < 0.000001) {
+        return n;
+    }
+    t = normalize(t);
+    vec3 b = cross(n, t) * world_tangent.w;
+    vec3 tangent_normal = texture(normal_texture, frag_uv).xyz * 2.0 - 1.0;
+    tangent_normal.xy *= normal_scale;
+    return normalize(mat3(t, b, n) * tangent_normal);
+}
+
+void main() {
+    vec4 base_color = base_color_factor * texture(base_color_texture, frag_uv);
+    if (alpha_cutoff >= 0.0 && base_color.a < alpha_cutoff) {
+        discard;
+    }
+    vec4 metallic_roughness = texture(metallic_roughness_texture, frag_uv);
+    float metallic = clamp(metallic_factor * metallic_roughness.b, 0.0, 1.0);
+    // Fully smooth surfaces turn point lights into invisible specks
+    float roughness = clamp(roughness_factor * metallic_roughness.g, 0.04, 1.0);
+    float occlusion = mix(1.0, texture(occlusion_texture, frag_uv).r, occlusion_strength);
+    vec3 emissive = emissive_factor * texture(emissive_texture, frag_uv).rgb;
+
+    vec3 n = surface_normal();
+    vec3 v = normalize(camera_position.xyz - world_position);
+    float n_dot_v = max(dot(n, v), 0.0001);
+    vec3 f0 = mix(vec3(0.04), base_color.rgb, metallic);
+    vec3 diffuse_color = base_color.rgb * (1.0 - metallic);
+
+    vec3 color = vec3(0.0);
+    for (uint i = 0u; i < light_count; i++) {
+        Light light = lights[i];
+        vec3 to_light = light.kind == DIRECTIONAL ? -light.direction : light.position - world_position;
+        vec3 l = normalize(to_light);
+        float n_dot_l = dot(n, l);
+        if (n_dot_l <= 0.0) {
+            continue;
+        }
+        vec3 h = normalize(v + l);
+        vec3 f = fresnel_schlick(max(dot(h, v), 0.0), f0);
+        float d = distribution_ggx(max(dot(n, h), 0.0), roughness);
+        float g = geometry_smith(n_dot_v, n_dot_l, roughness);
+        vec3 specular = d * g * f / (4.0 * n_dot_v * n_dot_l);
+        vec3 diffuse = (1.0 - f) * diffuse_color / PI;
+        vec3 radiance = light.color * light.intensity * attenuation(light, to_light) * shadow_factor(light, n, l);
+        color += (diffuse + specular) * radiance * n_dot_l;
+    }
+    color += ambient * diffuse_color * occlusion;
+    color += emissive;
+    out_color = vec4(color, base_color.a);
+}
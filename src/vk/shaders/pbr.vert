@@ -0,0 +1,34 @@
+#version 450
+
+// Attribute names have to match the fields of Vert
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 normal;
+layout(location = 2) in vec2 uv;
+layout(location = 3) in vec4 tangent;
+
+layout(set = 0, binding = 0) uniform Camera {
+    mat4 view;
+    mat4 projection;
+    mat4 view_projection;
+    vec4 camera_position;
+};
+
+layout(push_constant) uniform Model {
+    mat4 model;
+};
+
+layout(location = 0) out vec3 world_position;
+layout(location = 1) out vec3 world_normal;
+layout(location = 2) out vec2 frag_uv;
+layout(location = 3) out vec4 world_tangent;
+
+void main() {
+    vec4 world = model * vec4(position, 1.0);
+    // Inverse transpose keeps normals perpendicular under non uniform scale
+    mat3 normal_matrix = transpose(inverse(mat3(model)));
+    world_position = world.xyz;
+    world_normal = normal_matrix * normal;
+    world_tangent = vec4(mat3(model) * tangent.xyz, tangent.w);
+    frag_uv = uv;
+    gl_Position = view_projection * world;
+}
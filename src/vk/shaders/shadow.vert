@@ -0,0 +1,13 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+
+// Matches shadow::ShadowPushConstants
+layout(push_constant) uniform Shadow {
+    mat4 model;
+    mat4 light_view_projection;
+};
+
+void main() {
+    gl_Position = light_view_projection * model * vec4(position, 1.0);
+}
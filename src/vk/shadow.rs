@@ -0,0 +1,369 @@
+use glam::{Mat4, Vec3};
+use vulkano::buffer::{BufferContents, Subbuffer};
+use vulkano::buffer::allocator::SubbufferAllocator;
+use vulkano::device::Device;
+use vulkano::device::physical::PhysicalDevice;
+use vulkano::format::FormatFeatures;
+use vulkano::image::{Image, ImageCreateInfo, ImageType, ImageUsage};
+use vulkano::image::sampler::{Filter, Sampler, SamplerAddressMode, SamplerCreateInfo};
+use vulkano::image::view::{ImageView, ImageViewCreateInfo, ImageViewType};
+use vulkano::memory::allocator::{AllocationCreateInfo, MemoryTypeFilter, StandardMemoryAllocator};
+use vulkano::pipeline::graphics::GraphicsPipeline;
+use vulkano::pipeline::graphics::depth_stencil::CompareOp;
+use vulkano::render_pass::{Framebuffer, FramebufferCreateInfo, RenderPass};
+use std::f32::consts::FRAC_PI_2;
+use std::sync::Arc;
+
+use crate::vk::camera::{Camera, Projection};
+use crate::vk::debug;
+use crate::vk::image;
+use crate::vk::pipeline;
+use crate::vk::scene::{LightKind, Scene};
+use crate::vk::shader::{ShaderLanguage, ShaderProgram, ShaderStage, Shaders};
+
+// In the camera set next to the lights, i.e.
+// `layout(std430, set = 0, binding = 2) readonly buffer Shadows { ... }` and
+// `layout(set = 0, binding = 3) uniform sampler2DArrayShadow shadow_map;`
+pub const SHADOW_LAYERS_BINDING: u32 = 2;
+pub const SHADOW_MAP_BINDING: u32 = 3;
+
+// Near plane of point and spot light shadows, and where cascades start for cameras whose near plane is at or behind them
+const SHADOW_NEAR_PLANE: f32 = 0.05;
+
+#[derive(Clone, Debug)]
+pub struct ShadowConfig {
+    // Width and height of every layer in texels
+    pub resolution: u32,
+    // Layers in each shadow map, a directional light takes one per cascade, a spot light one and a point light six
+    // Shadow casting lights that don't fit any more are drawn unshadowed
+    pub max_layers: u32,
+    pub cascades: u32,
+    // 0 splits the cascades evenly, 1 logarithmically, which puts more of them near the camera
+    pub cascade_split_lambda: f32,
+    // Directional shadows end this far from the camera, also the range of point and spot lights without one
+    pub max_distance: f32,
+    // Rasterizer depth bias while rendering the shadow maps
+    pub depth_bias_constant: f32,
+    pub depth_bias_slope: f32,
+    // World units surfaces are pushed along their normal before being looked up, grows at grazing angles
+    pub normal_offset: f32,
+    // Filters (2 * radius + 1)^2 hardware compared taps
+    pub pcf_radius: u32,
+}
+
+impl Default for ShadowConfig {
+    fn default() -> Self {
+        ShadowConfig {
+            resolution: 1024,
+            max_layers: 8,
+            cascades: 4,
+            cascade_split_lambda: 0.75,
+            max_distance: 100.0,
+            depth_bias_constant: 1.25,
+            depth_bias_slope: 1.75,
+            normal_offset: 0.02,
+            pcf_radius: 1,
+        }
+    }
+}
+
+// Matches the ShadowLayer struct of shaders/pbr.frag under std430
+#[derive(BufferContents, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct GpuShadowLayer {
+    pub view_projection: [[f32; 4]; 4],
+    // View space distance from the camera where a directional light's cascade ends
+    pub split_depth: f32,
+    _padding: [f32; 3],
+}
+
+#[derive(BufferContents)]
+#[repr(C)]
+pub struct ShadowList {
+    pub normal_offset: f32,
+    pub pcf_radius: i32,
+    pub texel_size: f32,
+    pub count: u32,
+    // Never empty since buffers can't be, count says how many are in use
+    pub layers: [GpuShadowLayer],
+}
+
+// Pushed for every draw of the shadow passes, `layout(push_constant) uniform Shadow { mat4 model; mat4 light_view_projection; }`
+#[derive(BufferContents, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ShadowPushConstants {
+    pub model: [[f32; 4]; 4],
+    pub light_view_projection: [[f32; 4]; 4],
+}
+
+// Which shadow map layers the lights render to this frame
+pub struct ShadowPlan {
+    pub layers: Vec<GpuShadowLayer>,
+    // One per light in Scene::lights order, the first layer and layer count of the lights that got any
+    pub assignments: Vec<Option<(u32, u32)>>,
+}
+
+// Lights get layers in scene order until the shadow maps are full
+pub fn plan_shadows(scene: &Scene, camera: &Camera, config: &ShadowConfig) -> ShadowPlan {
+    let mut layers = Vec::new();
+    let mut assignments = Vec::new();
+    for (light, world) in scene.lights() {
+        let needed = match light.kind {
+            LightKind::Directional => config.cascades,
+            LightKind::Point => 6,
+            LightKind::Spot { .. } => 1,
+        };
+        if !light.cast_shadows || needed == 0 || layers.len() as u32 + needed > config.max_layers {
+            assignments.push(None);
+            continue;
+        }
+        let first = layers.len() as u32;
+        let position = world.w_axis.truncate();
+        let direction = world.transform_vector3(Vec3::NEG_Z).normalize_or_zero();
+        let far = light.range.unwrap_or(config.max_distance);
+        match light.kind {
+            LightKind::Directional => {
+                layers.extend(cascade_layers(camera, direction, config));
+            }
+            LightKind::Point => {
+                // Same face order as cube maps, the lighting shader picks the face by the major axis
+                let projection = Mat4::perspective_rh(FRAC_PI_2, 1.0, SHADOW_NEAR_PLANE, far);
+                for face in [Vec3::X, Vec3::NEG_X, Vec3::Y, Vec3::NEG_Y, Vec3::Z, Vec3::NEG_Z] {
+                    let view = Mat4::look_to_rh(position, face, up_for(face));
+                    layers.push(GpuShadowLayer::new(projection * view, f32::MAX));
+                }
+            }
+            LightKind::Spot { outer_cone_angle, .. } => {
+                let fov = (outer_cone_angle * 2.0).min(std::f32::consts::PI - 0.01);
+                let projection = Mat4::perspective_rh(fov, 1.0, SHADOW_NEAR_PLANE, far);
+                let view = Mat4::look_to_rh(position, direction, up_for(direction));
+                layers.push(GpuShadowLayer::new(projection * view, f32::MAX));
+            }
+        }
+        assignments.push(Some((first, needed)));
+    }
+    ShadowPlan { layers, assignments }
+}
+
+pub fn write_shadow_layers(allocator: &SubbufferAllocator, plan: &ShadowPlan, config: &ShadowConfig) -> Subbuffer<ShadowList> {
+    let buffer = allocator.allocate_unsized::<ShadowList>(plan.layers.len().max(1) as u64)
+        .expect("Failed to allocate shadow layer buffer");
+    {
+        let mut list = buffer.write().expect("Shadow layer buffer still in use");
+        list.normal_offset = config.normal_offset;
+        list.pcf_radius = config.pcf_radius as i32;
+        list.texel_size = 1.0 / config.resolution as f32;
+        list.count = plan.layers.len() as u32;
+        list.layers[..plan.layers.len()].copy_from_slice(&plan.layers);
+    }
+    buffer
+}
+
+impl GpuShadowLayer {
+    fn new(view_projection: Mat4, split_depth: f32) -> Self {
+        GpuShadowLayer { view_projection: view_projection.to_cols_array_2d(), split_depth, _padding: [0.0; 3] }
+    }
+}
+
+fn up_for(direction: Vec3) -> Vec3 {
+    if direction.dot(Vec3::Y).abs() > 0.99 { Vec3::Z } else { Vec3::Y }
+}
+
+// Splits the camera's view range into cascades, each covered by an orthographic projection along the light
+fn cascade_layers(camera: &Camera, direction: Vec3, config: &ShadowConfig) -> Vec<GpuShadowLayer> {
+    let (near, far) = match camera.projection {
+        Projection::Perspective { near, far, .. } | Projection::Orthographic { near, far, .. } => (near, far.min(config.max_distance)),
+    };
+    // Logarithmic splits need a positive near plane, orthographic cameras often sit theirs at 0
+    let near = near.max(SHADOW_NEAR_PLANE);
+    let far = far.max(near);
+    let light_view = Mat4::look_to_rh(Vec3::ZERO, direction, up_for(direction));
+    let mut split_near = near;
+    (1..=config.cascades).map(|i| {
+        let fraction = i as f32 / config.cascades as f32;
+        let logarithmic = near * (far / near).powf(fraction);
+        let uniform = near + (far - near) * fraction;
+        let split_far = config.cascade_split_lambda * logarithmic + (1.0 - config.cascade_split_lambda) * uniform;
+        let corners = frustum_corners(camera, split_near, split_far);
+        split_near = split_far;
+
+        // A bounding sphere keeps the projection's size constant as the camera turns, and snapping its center to
+        // whole texels keeps shadow edges from shimmering as the camera moves
+        let center = corners.iter().sum::<Vec3>() / corners.len() as f32;
+        let radius = corners.iter().map(|corner| corner.distance(center)).fold(0.0, f32::max);
+        let radius = (radius * 16.0).ceil() / 16.0;
+        let texel = 2.0 * radius / config.resolution as f32;
+        let mut light_center = light_view.transform_point3(center);
+        light_center.x = (light_center.x / texel).floor() * texel;
+        light_center.y = (light_center.y / texel).floor() * texel;
+        // Casters up to max_distance behind the cascade still land in it
+        let projection = Mat4::orthographic_rh(
+            light_center.x - radius,
+            light_center.x + radius,
+            light_center.y - radius,
+            light_center.y + radius,
+            -light_center.z - radius - config.max_distance,
+            -light_center.z + radius
+        );
+        GpuShadowLayer::new(projection * light_view, split_far)
+    }).collect()
+}
+
+// World space corners of the camera frustum between two view distances
+fn frustum_corners(camera: &Camera, near: f32, far: f32) -> Vec<Vec3> {
+    let inverse_view = camera.view_matrix().inverse();
+    let half_height = |distance: f32| match camera.projection {
+        Projection::Perspective { fov_y, .. } => (fov_y / 2.0).tan() * distance,
+        Projection::Orthographic { height, .. } => height / 2.0,
+    };
+    let mut corners = Vec::with_capacity(8);
+    for distance in [near, far] {
+        let half_height = half_height(distance);
+        let half_width = half_height * camera.aspect;
+        for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+            corners.push(inverse_view.transform_point3(Vec3::new(x * half_width, y * half_height, -distance)));
+        }
+    }
+    corners
+}
+
+// What the lighting pass binds from a frame's shadow passes
+pub struct ShadowFrame {
+    pub plan: ShadowPlan,
+    pub layers: Subbuffer<ShadowList>,
+    pub view: Arc<ImageView>,
+    pub sampler: Arc<Sampler>,
+}
+
+// Layered depth image with a framebuffer per layer to render into and an array view to sample
+pub struct ShadowMap {
+    pub view: Arc<ImageView>,
+    pub framebuffers: Vec<Arc<Framebuffer>>,
+    // Every layer gets cleared once so unused ones are still in a layout that can be sampled
+    pub(crate) initialized: bool,
+}
+
+impl ShadowMap {
+    pub fn new(memory_allocator: Arc<StandardMemoryAllocator>, render_pass: Arc<RenderPass>, config: &ShadowConfig) -> Self {
+        assert!(config.max_layers > 0, "Shadow maps need at least one layer");
+        let format = render_pass.attachments()[0].format;
+        let image = Image::new(
+            memory_allocator,
+            ImageCreateInfo {
+                image_type: ImageType::Dim2d,
+                format,
+                extent: [config.resolution, config.resolution, 1],
+                array_layers: config.max_layers,
+                usage: ImageUsage::DEPTH_STENCIL_ATTACHMENT | ImageUsage::SAMPLED,
+                ..Default::default()
+            },
+            AllocationCreateInfo {
+                memory_type_filter: MemoryTypeFilter::PREFER_DEVICE,
+                ..Default::default()
+            }
+        ).expect("Failed to create shadow map");
+        debug::set_object_name(&*image, &format!("shadow map {}x{}x{}", config.resolution, config.resolution, config.max_layers));
+        let view = ImageView::new(image.clone(), ImageViewCreateInfo {
+            view_type: ImageViewType::Dim2dArray,
+            ..ImageViewCreateInfo::from_image(&image)
+        }).expect("Failed to create shadow map view");
+        let framebuffers = (0..config.max_layers).map(|layer| {
+            let mut create_info = ImageViewCreateInfo::from_image(&image);
+            create_info.view_type = ImageViewType::Dim2d;
+            create_info.subresource_range.array_layers = layer..layer + 1;
+            let layer_view = ImageView::new(image.clone(), create_info).expect("Failed to create shadow map layer view");
+            Framebuffer::new(render_pass.clone(), FramebufferCreateInfo {
+                attachments: vec![layer_view],
+                ..Default::default()
+            }).expect("Failed to create shadow framebuffer")
+        }).collect();
+        ShadowMap { view, framebuffers, initialized: false }
+    }
+}
+
+// Everything the shadow passes share across windows
+pub struct ShadowRenderer {
+    pub render_pass: Arc<RenderPass>,
+    pub pipeline: Arc<GraphicsPipeline>,
+    // Compares against the stored depth, returning how lit the looked up texel is
+    pub sampler: Arc<Sampler>,
+}
+
+impl ShadowRenderer {
+    pub fn new(device: Arc<Device>, physical_device: &PhysicalDevice, shaders: &mut Shaders, config: &ShadowConfig) -> Self {
+        let format = image::choose_depth_format(physical_device, FormatFeatures::DEPTH_STENCIL_ATTACHMENT | FormatFeatures::SAMPLED_IMAGE);
+        let vertex = shaders.load_shader_from_string(include_str!("shaders/shadow.vert"), ShaderLanguage::Glsl, ShaderStage::Vertex, "main");
+        let program = shaders.register_program("shadow", ShaderProgram::from_stages([vertex]));
+        let render_pass = pipeline::create_shadow_render_pass(device.clone(), format);
+        let pipeline = pipeline::create_shadow_pipeline(
+            device.clone(),
+            shaders,
+            program,
+            render_pass.clone(),
+            config.depth_bias_constant,
+            config.depth_bias_slope
+        );
+        // Linear filtering of compared depth gives a 2x2 PCF for free where the format supports it
+        let filter = match physical_device.format_properties(format) {
+            Ok(properties) if properties.optimal_tiling_features.intersects(FormatFeatures::SAMPLED_IMAGE_FILTER_LINEAR) => Filter::Linear,
+            _ => Filter::Nearest,
+        };
+        let sampler = Sampler::new(device, SamplerCreateInfo {
+            mag_filter: filter,
+            min_filter: filter,
+            address_mode: [SamplerAddressMode::ClampToEdge; 3],
+            compare: Some(CompareOp::LessOrEqual),
+            ..Default::default()
+        }).expect("Failed to create shadow sampler");
+        ShadowRenderer { render_pass, pipeline, sampler }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vk::scene::{Light, Node};
+
+    fn directional_scene() -> Scene {
+        let mut scene = Scene::new();
+        scene.add(Node::new("sun").with_light(Light {
+            kind: LightKind::Directional,
+            color: [1.0; 3],
+            intensity: 1.0,
+            range: None,
+            cast_shadows: true,
+        }), None);
+        scene.update_world_transforms();
+        scene
+    }
+
+    #[test]
+    fn cascades_are_finite_for_a_near_plane_at_zero() {
+        let camera = Camera::new(Projection::Orthographic { height: 10.0, near: 0.0, far: 50.0 });
+        let config = ShadowConfig::default();
+        let plan = plan_shadows(&directional_scene(), &camera, &config);
+        assert_eq!(plan.assignments, vec![Some((0, config.cascades))]);
+        for layer in &plan.layers {
+            assert!(layer.split_depth.is_finite());
+            assert!(layer.view_projection.iter().flatten().all(|value| value.is_finite()));
+        }
+    }
+
+    #[test]
+    fn lights_that_dont_fit_are_unshadowed() {
+        let camera = Camera::default();
+        let config = ShadowConfig { max_layers: 6, cascades: 4, ..Default::default() };
+        let mut scene = directional_scene();
+        scene.add(Node::new("second sun").with_light(Light {
+            kind: LightKind::Directional,
+            color: [1.0; 3],
+            intensity: 1.0,
+            range: None,
+            cast_shadows: true,
+        }), None);
+        scene.update_world_transforms();
+        let plan = plan_shadows(&scene, &camera, &config);
+        assert_eq!(plan.assignments, vec![Some((0, 4)), None]);
+        assert_eq!(plan.layers.len(), 4);
+    }
+}
@@ -0,0 +1,209 @@
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug)]
+pub struct TimeConfig {
+    // Length of one fixed update step
+    pub fixed_timestep: Duration,
+    // Frame deltas are clamped to this so a long stall doesn't queue up a burst of fixed updates
+    pub max_delta: Duration,
+    // Frames per second to sleep down to, None draws as fast as the present mode allows
+    pub max_frame_rate: Option<f64>,
+    // Advances exactly this much per frame regardless of wall time, for reproducible renders
+    pub deterministic_delta: Option<Duration>,
+}
+
+impl Default for TimeConfig {
+    fn default() -> Self {
+        TimeConfig {
+            fixed_timestep: Duration::from_secs_f64(1.0 / 60.0),
+            max_delta: Duration::from_millis(250),
+            max_frame_rate: None,
+            deterministic_delta: None,
+        }
+    }
+}
+
+impl TimeConfig {
+    pub fn deterministic(delta: Duration) -> Self {
+        TimeConfig { deterministic_delta: Some(delta), ..Default::default() }
+    }
+}
+
+fn validate_config(config: &TimeConfig) {
+    assert!(!config.fixed_timestep.is_zero(), "Fixed timestep can't be zero");
+    if let Some(max_frame_rate) = config.max_frame_rate {
+        assert!(max_frame_rate > 0.0, "Max frame rate has to be positive, got {}", max_frame_rate);
+    }
+}
+
+pub struct Clock {
+    config: TimeConfig,
+    last_tick: Option<Instant>,
+    delta: Duration,
+    elapsed: Duration,
+    frame_count: u64,
+    // Time not yet consumed by fixed steps
+    accumulator: Duration,
+    fixed_steps: u32,
+}
+
+impl Clock {
+    pub fn new(config: TimeConfig) -> Self {
+        validate_config(&config);
+        Clock {
+            config,
+            last_tick: None,
+            delta: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            frame_count: 0,
+            accumulator: Duration::ZERO,
+            fixed_steps: 0,
+        }
+    }
+
+    pub fn config(&self) -> &TimeConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: TimeConfig) {
+        validate_config(&config);
+        self.config = config;
+    }
+
+    // Sleeps until the frame rate cap allows the next frame to start
+    pub fn wait_for_next_frame(&self) {
+        let (max_frame_rate, last_tick) = match (self.config.max_frame_rate, self.last_tick) {
+            (Some(max_frame_rate), Some(last_tick)) => (max_frame_rate, last_tick),
+            _ => return,
+        };
+        let frame_time = Duration::from_secs_f64(1.0 / max_frame_rate);
+        let since_last = last_tick.elapsed();
+        if since_last < frame_time {
+            std::thread::sleep(frame_time - since_last);
+        }
+    }
+
+    // Starts a new frame, returns how many fixed steps should run in it
+    // Outside deterministic mode the first frame has a zero delta
+    pub fn tick(&mut self) -> u32 {
+        let now = Instant::now();
+        // The deterministic delta isn't clamped, it's what the frame is meant to advance by
+        self.delta = match (self.config.deterministic_delta, self.last_tick) {
+            (Some(delta), _) => delta,
+            (None, Some(last_tick)) => (now - last_tick).min(self.config.max_delta),
+            (None, None) => Duration::ZERO,
+        };
+        self.last_tick = Some(now);
+        self.elapsed += self.delta;
+        self.frame_count += 1;
+
+        self.accumulator += self.delta;
+        self.fixed_steps = 0;
+        while self.accumulator >= self.config.fixed_timestep {
+            self.accumulator -= self.config.fixed_timestep;
+            self.fixed_steps += 1;
+        }
+        self.fixed_steps
+    }
+
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+
+    // Sum of the frame deltas, so it stops advancing with them when clamped and follows the deterministic delta
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn elapsed_seconds(&self) -> f32 {
+        self.elapsed.as_secs_f32()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn fixed_timestep(&self) -> Duration {
+        self.config.fixed_timestep
+    }
+
+    pub fn fixed_steps(&self) -> u32 {
+        self.fixed_steps
+    }
+
+    // How far the frame is between the last fixed step and the next one, in [0, 1)
+    // Blend the previous and current simulation states with it when drawing
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.config.fixed_timestep.as_secs_f32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deterministic_clock(delta_ms: u64, fixed_timestep_ms: u64) -> Clock {
+        Clock::new(TimeConfig {
+            fixed_timestep: Duration::from_millis(fixed_timestep_ms),
+            ..TimeConfig::deterministic(Duration::from_millis(delta_ms))
+        })
+    }
+
+    #[test]
+    fn deterministic_delta_advances_elapsed_exactly() {
+        let mut clock = deterministic_clock(16, 10);
+        for _ in 0..100 {
+            clock.tick();
+        }
+        assert_eq!(clock.delta(), Duration::from_millis(16));
+        assert_eq!(clock.elapsed(), Duration::from_millis(1600));
+        assert_eq!(clock.frame_count(), 100);
+    }
+
+    #[test]
+    fn deterministic_delta_is_not_clamped() {
+        let mut clock = deterministic_clock(1000, 100);
+        assert_eq!(clock.tick(), 10);
+        assert_eq!(clock.delta(), Duration::from_secs(1));
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn accumulator_carries_over_between_frames() {
+        let mut clock = deterministic_clock(25, 10);
+        // 25, 50, 75, 100 ms accumulated, fixed steps consume 10 ms each
+        assert_eq!(clock.tick(), 2);
+        assert!((clock.alpha() - 0.5).abs() < 1e-6);
+        assert_eq!(clock.tick(), 3);
+        assert!(clock.alpha().abs() < 1e-6);
+        assert_eq!(clock.tick(), 2);
+        assert_eq!(clock.fixed_steps(), 2);
+        assert_eq!(clock.tick(), 3);
+    }
+
+    #[test]
+    fn frame_shorter_than_fixed_timestep_runs_no_steps() {
+        let mut clock = deterministic_clock(4, 10);
+        assert_eq!(clock.tick(), 0);
+        assert_eq!(clock.tick(), 0);
+        assert_eq!(clock.tick(), 1);
+        assert!((clock.alpha() - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_max_frame_rate_is_rejected() {
+        Clock::new(TimeConfig { max_frame_rate: Some(0.0), ..Default::default() });
+    }
+
+    #[test]
+    #[should_panic]
+    fn nan_max_frame_rate_is_rejected() {
+        let mut clock = Clock::new(TimeConfig::default());
+        clock.set_config(TimeConfig { max_frame_rate: Some(f64::NAN), ..Default::default() });
+    }
+}
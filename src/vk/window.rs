@@ -0,0 +1,333 @@
+use winit::dpi::{LogicalSize, PhysicalSize, Size};
+use winit::event_loop::EventLoopWindowTarget;
+use winit::monitor::{MonitorHandle, VideoMode};
+use winit::window::{Fullscreen, Icon, Window, WindowBuilder, WindowId};
+use vulkano::instance::Instance;
+use vulkano::device::{Device, Queue};
+use vulkano::device::physical::PhysicalDevice;
+use vulkano::swapchain::{Surface, Swapchain, SwapchainAcquireFuture};
+use vulkano::sync::GpuFuture;
+use vulkano::image::Image;
+use vulkano::format::FormatFeatures;
+use vulkano::render_pass::{RenderPass, Framebuffer};
+use vulkano::pipeline::graphics::viewport::Viewport;
+use vulkano::command_buffer::PrimaryAutoCommandBuffer;
+use vulkano::command_buffer::allocator::StandardCommandBufferAllocator;
+use vulkano::memory::allocator::StandardMemoryAllocator;
+use vulkano::buffer::Subbuffer;
+use std::sync::Arc;
+
+use crate::vk::image::{self, SwapchainConfig, SwapchainStatus};
+use crate::vk::pipeline;
+use crate::vk::frame::{FramesInFlight, PerFrame};
+use crate::vk::buffer;
+use crate::vk::camera::{Camera, CameraUniform};
+use crate::vk::scene::NodeId;
+use crate::vk::shadow::{ShadowConfig, ShadowMap};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FullscreenMode {
+    Windowed,
+    // Borderless window covering the primary monitor
+    Borderless,
+    // Takes over the primary monitor with the video mode closest to the window size
+    Exclusive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DpiMode {
+    // Sizes are in logical pixels and get multiplied by the monitor's scale factor
+    Logical,
+    // Sizes are in physical pixels regardless of the scale factor
+    Physical,
+}
+
+// RGBA8 pixels, row major
+#[derive(Clone, Debug)]
+pub struct WindowIcon {
+    pub rgba: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct WindowConfig {
+    pub title: String,
+    // None lets the platform pick
+    pub size: Option<[u32; 2]>,
+    pub min_size: Option<[u32; 2]>,
+    pub resizable: bool,
+    pub fullscreen: FullscreenMode,
+    pub decorations: bool,
+    // Also makes the swapchain pick a non-opaque composite alpha when the surface supports one
+    pub transparent: bool,
+    pub dpi: DpiMode,
+    pub icon: Option<WindowIcon>,
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            title: "REngine".to_string(),
+            size: Some([1280, 720]),
+            min_size: None,
+            resizable: true,
+            fullscreen: FullscreenMode::Windowed,
+            decorations: true,
+            transparent: false,
+            dpi: DpiMode::Logical,
+            icon: None,
+        }
+    }
+}
+
+impl WindowConfig {
+    fn size(&self, size: [u32; 2]) -> Size {
+        match self.dpi {
+            DpiMode::Logical => LogicalSize::new(size[0], size[1]).into(),
+            DpiMode::Physical => PhysicalSize::new(size[0], size[1]).into(),
+        }
+    }
+}
+
+// Prefers the mode matching the requested size, then the largest, then the highest refresh rate
+fn choose_video_mode(monitor: &MonitorHandle, size: Option<[u32; 2]>) -> Option<VideoMode> {
+    monitor.video_modes().max_by_key(|mode| {
+        let mode_size = mode.size();
+        let matches = size.is_some_and(|size| mode_size.width == size[0] && mode_size.height == size[1]);
+        (matches, mode_size.width * mode_size.height, mode.refresh_rate_millihertz())
+    })
+}
+
+pub fn create_window(event_loop: &EventLoopWindowTarget<()>, config: &WindowConfig) -> Arc<Window> {
+    let mut builder = WindowBuilder::new()
+        .with_title(config.title.clone())
+        .with_resizable(config.resizable)
+        .with_decorations(config.decorations)
+        .with_transparent(config.transparent);
+    if let Some(size) = config.size {
+        builder = builder.with_inner_size(config.size(size));
+    }
+    if let Some(min_size) = config.min_size {
+        builder = builder.with_min_inner_size(config.size(min_size));
+    }
+    if let Some(icon) = &config.icon {
+        let icon = Icon::from_rgba(icon.rgba.clone(), icon.width, icon.height).expect("Invalid window icon");
+        builder = builder.with_window_icon(Some(icon));
+    }
+
+    let monitor = event_loop.primary_monitor().or_else(|| event_loop.available_monitors().next());
+    let fullscreen = match config.fullscreen {
+        FullscreenMode::Windowed => None,
+        FullscreenMode::Borderless => Some(Fullscreen::Borderless(monitor)),
+        FullscreenMode::Exclusive => match monitor.as_ref().and_then(|monitor| choose_video_mode(monitor, config.size)) {
+            Some(video_mode) => Some(Fullscreen::Exclusive(video_mode)),
+            None => {
+                log::warn!("No video mode available for exclusive fullscreen, using borderless");
+                Some(Fullscreen::Borderless(monitor))
+            }
+        },
+    };
+    builder = builder.with_fullscreen(fullscreen);
+
+    Arc::new(builder.build(event_loop).expect("Failed to create window"))
+}
+
+// What a window draws, every window is lit by all of the scene's lights
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowContent {
+    Scene,
+    // Only the node and everything below it, i.e. a material preview
+    Subtree(NodeId),
+}
+
+// A window opened on the next event loop turn, see VkApp::request_window
+pub(crate) struct WindowRequest {
+    pub window: WindowConfig,
+    pub swapchain: SwapchainConfig,
+    pub content: WindowContent,
+    pub camera: Camera,
+}
+
+// A window with everything needed to present to it: surface, swapchain, render pass, framebuffers
+// and its own frames in flight. The device, allocators and pipelines are shared between windows
+pub struct RenderWindow {
+    pub window: Arc<Window>,
+    pub surface: Arc<Surface>,
+    pub swapchain: Arc<Swapchain>,
+    pub swapchain_config: SwapchainConfig,
+    pub swapchain_images: Vec<Arc<Image>>,
+    pub render_pass: Arc<RenderPass>,
+    pub framebuffers: Vec<Arc<Framebuffer>>,
+    pub viewport: Viewport,
+    pub frames: FramesInFlight,
+    pub swapchain_status: SwapchainStatus,
+    // One per swapchain image, rewritten right before the image's command buffer is submitted
+    pub camera_buffers: Vec<Subbuffer<CameraUniform>>,
+    // Its aspect ratio is overwritten from the viewport when drawing
+    pub camera: Camera,
+    pub content: WindowContent,
+    // One per frame in flight, created the first frame the scene samples shadows
+    pub shadow_maps: Option<PerFrame<ShadowMap>>,
+    memory_allocator: Arc<StandardMemoryAllocator>,
+}
+
+impl RenderWindow {
+    pub fn new(
+        instance: Arc<Instance>,
+        device: Arc<Device>,
+        queue: &Queue,
+        memory_allocator: Arc<StandardMemoryAllocator>,
+        window: Arc<Window>,
+        swapchain_config: SwapchainConfig,
+        frames_in_flight: usize
+    ) -> Self {
+        let physical_device = device.physical_device().clone();
+        let surface = Surface::from_window(instance, window.clone()).unwrap();
+        if !physical_device.surface_support(queue.queue_family_index(), &surface).unwrap_or(false) {
+            panic!("The device can't present to window `{}`", window.title());
+        }
+        let depth_format = image::choose_depth_format(&physical_device, FormatFeatures::DEPTH_STENCIL_ATTACHMENT);
+        let (swapchain, swapchain_images) = image::create_swapchain(device.clone(), window.clone(), surface.clone(), physical_device, &swapchain_config);
+        let render_pass = pipeline::create_render_pass(device, swapchain.image_format(), depth_format);
+        let framebuffers = pipeline::create_framebuffers(memory_allocator.clone(), render_pass.clone(), swapchain_images.clone());
+        let frames = FramesInFlight::new(frames_in_flight, swapchain_images.len());
+        let viewport = Viewport {
+            offset: [0.0, 0.0],
+            extent: [swapchain.image_extent()[0] as f32, swapchain.image_extent()[1] as f32],
+            depth_range: 0.0..=1.0,
+        };
+        let camera_buffers = create_camera_buffers(memory_allocator.clone(), swapchain_images.len());
+
+        RenderWindow {
+            window,
+            surface,
+            swapchain,
+            swapchain_config,
+            swapchain_images,
+            render_pass,
+            framebuffers,
+            viewport,
+            frames,
+            swapchain_status: SwapchainStatus::Ok,
+            camera_buffers,
+            camera: Camera::default(),
+            content: WindowContent::Scene,
+            shadow_maps: None,
+            memory_allocator,
+        }
+    }
+
+    pub fn id(&self) -> WindowId {
+        self.window.id()
+    }
+
+    // The shadow map of the frame being recorded, the frame's slot was waited for so it's free to render into
+    // Created on first use, and again after set_shadow_config cleared them
+    pub(crate) fn current_shadow_map(&mut self, render_pass: &Arc<RenderPass>, config: &ShadowConfig) -> &mut ShadowMap {
+        if self.shadow_maps.is_none() {
+            self.shadow_maps = Some(PerFrame::new(&self.frames, |_| {
+                ShadowMap::new(self.memory_allocator.clone(), render_pass.clone(), config)
+            }));
+        }
+        self.shadow_maps.as_mut().unwrap().current_mut(&self.frames)
+    }
+
+    pub fn is_minimized(&self) -> bool {
+        let size = self.window.inner_size();
+        self.window.is_minimized().unwrap_or(false) || size.width == 0 || size.height == 0
+    }
+
+    // The one place the swapchain and everything depending on it gets recreated, for resizes, suboptimal and
+    // out of date swapchains, lost surfaces and configuration changes
+    // Returns false while the window has no area, the caller retries once it does
+    pub fn recreate_swapchain(&mut self, instance: Arc<Instance>, device: Arc<Device>, physical_device: Arc<PhysicalDevice>) -> bool {
+        let dimensions = self.window.inner_size();
+        if dimensions.width == 0 || dimensions.height == 0 {
+            return false;
+        }
+        self.frames.wait_idle();
+        let old_format = self.swapchain.image_format();
+        let (swapchain, swapchain_images) = if self.swapchain_status == SwapchainStatus::SurfaceLost {
+            log::info!("Surface lost, recreating it");
+            self.surface = Surface::from_window(instance, self.window.clone()).unwrap();
+            image::create_swapchain(
+                device.clone(),
+                self.window.clone(),
+                self.surface.clone(),
+                physical_device,
+                &self.swapchain_config
+            )
+        } else {
+            image::reconfigure_swapchain(
+                self.swapchain.clone(),
+                physical_device,
+                &self.swapchain_config,
+                dimensions.into()
+            )
+        };
+        self.swapchain = swapchain;
+        self.swapchain_images = swapchain_images;
+        self.frames.reset_images(self.swapchain_images.len());
+        if self.camera_buffers.len() != self.swapchain_images.len() {
+            self.camera_buffers = create_camera_buffers(self.memory_allocator.clone(), self.swapchain_images.len());
+        }
+        if self.swapchain.image_format() != old_format {
+            let depth_format = self.render_pass.attachments()[1].format;
+            self.render_pass = pipeline::create_render_pass(device, self.swapchain.image_format(), depth_format);
+        }
+        self.viewport.extent = [self.swapchain.image_extent()[0] as f32, self.swapchain.image_extent()[1] as f32];
+        self.framebuffers = pipeline::create_framebuffers(
+            self.memory_allocator.clone(),
+            self.render_pass.clone(),
+            self.swapchain_images.clone()
+        );
+        self.swapchain_status = SwapchainStatus::Ok;
+        true
+    }
+
+    // Acquires an image and waits until it can be recorded for, writing the camera into its uniform buffer
+    // None when no image could be acquired, swapchain_status says why
+    pub fn acquire_frame(&mut self, device: Arc<Device>, camera: &CameraUniform) -> Option<AcquiredFrame> {
+        let (image_result, acquire_status) = image::obtain_next_swapchain_image(self.swapchain.clone());
+        self.swapchain_status = self.swapchain_status.merge(acquire_status);
+        let (image_idx, acquire_future) = image_result?;
+        let previous_future = self.frames.begin(device, image_idx);
+        // begin waited for the last frame that used this image, so its camera buffer is free
+        *self.camera_buffers[image_idx as usize].write().expect("Camera buffer still in use") = *camera;
+        Some(AcquiredFrame { image_idx, acquire_future, previous_future })
+    }
+
+    // Submits the command buffer recorded for the acquired image and presents it, remembering if the swapchain
+    // needs recreating
+    pub fn present_frame(
+        &mut self,
+        queue: Arc<Queue>,
+        frame: AcquiredFrame,
+        command_buffer: Arc<PrimaryAutoCommandBuffer<Arc<StandardCommandBufferAllocator>>>
+    ) {
+        let (fence, present_status) = image::present_swapchain_image_with_fence(
+            self.swapchain.clone(),
+            queue,
+            command_buffer,
+            frame.image_idx,
+            frame.acquire_future,
+            frame.previous_future,
+        );
+        self.frames.end(fence);
+        self.swapchain_status = self.swapchain_status.merge(present_status);
+    }
+}
+
+// A swapchain image between acquire_frame and present_frame
+pub struct AcquiredFrame {
+    pub image_idx: u32,
+    acquire_future: SwapchainAcquireFuture,
+    previous_future: Box<dyn GpuFuture>,
+}
+
+fn create_camera_buffers(memory_allocator: Arc<StandardMemoryAllocator>, count: usize) -> Vec<Subbuffer<CameraUniform>> {
+    (0..count)
+        .map(|_| buffer::create_uniform_buffer(memory_allocator.clone(), Camera::default().uniform()))
+        .collect()
+}